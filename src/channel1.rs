@@ -59,6 +59,12 @@ impl<T> OneShotChannel<T> {
     }
 }
 
+impl<T> Default for OneShotChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Drop for OneShotChannel<T> {
     fn drop(&mut self) {
         // Atomic access is unnecessary.
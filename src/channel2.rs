@@ -35,6 +35,12 @@ impl<T> OneShotChannel<T> {
     }
 }
 
+impl<T> Default for OneShotChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 unsafe impl<T> Sync for OneShotChannel<T> where T: Send {}
 
 impl<T> Drop for OneShotChannel<T> {
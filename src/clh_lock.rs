@@ -0,0 +1,141 @@
+use std::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering::*},
+};
+
+use crate::{condvar::Relock, mcs_lock::wait_while_locked};
+
+/// A thread's entry in the queue.
+#[repr(align(64))]
+struct Node {
+    /// Set until the thread that queued this node unlocks.
+    locked: AtomicBool,
+}
+
+/// A CLH queue lock.
+///
+/// Like `McsLock`, waiting threads queue up and hand the lock over in FIFO
+/// order. But instead of spinning on its own node, each thread spins on the
+/// node of the thread ahead of it, so the queue needs no `next` links, and
+/// unlocking is a single store.
+///
+/// The nodes are allocated on the heap: a node is still in use by the next
+/// thread after its own thread has unlocked, so that next thread frees it.
+/// This lets `lock` hand out a guard, like the other locks.
+pub struct ClhLock<T> {
+    /// The node of the last thread to queue up, or null if no thread has
+    /// locked yet.
+    tail: AtomicPtr<Node>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for ClhLock<T> where T: Send {}
+
+impl<T> ClhLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> ClhLockGuard<'_, T> {
+        let node = NonNull::from(Box::leak(Box::new(Node {
+            locked: AtomicBool::new(true),
+        })));
+        // AcqRel: acquire the data released by the previous holder, if it has
+        // unlocked already. And release our node to the thread queueing up next.
+        let prev = self.tail.swap(node.as_ptr(), AcqRel);
+        if !prev.is_null() {
+            // Not a `Box` yet: the previous thread still stores to its node
+            // when unlocking.
+            //
+            // Safety: The previous node is only freed by us, below.
+            wait_while_locked(unsafe { &(*prev).locked });
+            // Safety: The previous thread has unlocked, and won't touch its
+            // node again.
+            drop(unsafe { Box::from_raw(prev) });
+        }
+        ClhLockGuard { lock: self, node }
+    }
+}
+
+impl<T> Drop for ClhLock<T> {
+    fn drop(&mut self) {
+        // The last node to be queued is left behind for the next thread, which
+        // will never come now.
+        let tail = *self.tail.get_mut();
+        if !tail.is_null() {
+            drop(unsafe { Box::from_raw(tail) });
+        }
+    }
+}
+
+pub struct ClhLockGuard<'a, T> {
+    lock: &'a ClhLock<T>,
+    node: NonNull<Node>,
+}
+
+unsafe impl<'a, T: Sync> Sync for ClhLockGuard<'a, T> {}
+
+impl<'a, T> Deref for ClhLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The existence of this Guard instance guarantees this is the
+        // exclusive reference to the data.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for ClhLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for ClhLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // The node now belongs to the next thread (or the lock). Don't touch
+        // it after this.
+        unsafe { self.node.as_ref() }.locked.store(false, Release);
+    }
+}
+
+impl<'a, T> Relock<'a> for ClhLockGuard<'a, T> {
+    type Lock = ClhLock<T>;
+    type State = ();
+
+    fn unlock(self) -> (&'a ClhLock<T>, ()) {
+        let lock = self.lock;
+        drop(self);
+        (lock, ())
+    }
+
+    fn relock(lock: &'a ClhLock<T>, _: ()) -> Self {
+        lock.lock()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClhLock;
+    use std::thread;
+
+    #[test]
+    fn add() {
+        let lock = ClhLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 8000);
+    }
+}
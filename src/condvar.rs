@@ -1,40 +1,203 @@
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering::*};
+use std::{
+    ops::DerefMut,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering::*},
+    time::{Duration, Instant},
+};
 
-use atomic_wait::{wait, wake_all, wake_one};
+use atomic_wait::{wake_all, wake_one};
 
-use crate::mutex::MutexGuard;
+use crate::{futex, mutex::Mutex};
+
+/// A lock guard that can be released while waiting on a `Condvar`, and
+/// acquired again afterwards.
+pub trait Relock<'a>: Sized {
+    type Lock: ?Sized + 'a;
+
+    /// What the guard needs to remember while unlocked, to come back the same
+    /// as it was, e.g. whether it poisons the lock.
+    type State;
+
+    /// Unlock, returning the lock this guard was for.
+    fn unlock(self) -> (&'a Self::Lock, Self::State);
+
+    /// Lock again, after waiting.
+    fn relock(lock: &'a Self::Lock, state: Self::State) -> Self;
+
+    /// The futex word that threads waiting for the lock sleep on, if any.
+    ///
+    /// `notify_all` moves the waiters over to it, so that they're woken up one
+    /// at a time as the lock is released, instead of all at once.
+    fn futex(_lock: &Self::Lock) -> Option<&AtomicU32> {
+        None
+    }
+
+    /// Lock again, after possibly being moved over to the lock's `futex`.
+    ///
+    /// This must leave the lock marked as contended, so that unlocking wakes
+    /// up the next waiter that was moved over.
+    fn relock_contended(lock: &'a Self::Lock, state: Self::State) -> Self {
+        Self::relock(lock, state)
+    }
+}
+
+/// Set in `Condvar::counter` once a waiter has seen its current value.
+const OBSERVED: u32 = 1;
 
 pub struct Condvar {
+    /// Bumped (by 2) on every notification, but only if a waiter has seen the
+    /// current value, i.e. if the `OBSERVED` bit is set.
+    ///
+    /// Waiters sleep only if the counter still has the value they saw. If the
+    /// counter were bumped on every notification, exactly 2^32 notifications
+    /// between a waiter seeing the value and going to sleep would bring it
+    /// back to the same value, and that waiter would miss all of them. Now the
+    /// counter has to be seen again before every bump, so that takes 2^31
+    /// other waits to start (each with the lock held) while this waiter is
+    /// still on its way to sleep.
     counter: AtomicU32,
     num_waiters: AtomicUsize,
+    /// Where `notify_all` can move the waiters to.
+    lock_futex: Mutex<LockFutex>,
+    /// Address of the lock used by the first `wait`, or 0. Only tracked in
+    /// debug builds, to catch waits on different locks.
+    #[cfg(debug_assertions)]
+    lock_addr: AtomicUsize,
 }
 
 impl Condvar {
     pub const fn new() -> Self {
+        Self::with_counter(0)
+    }
+
+    const fn with_counter(counter: u32) -> Self {
         Self {
-            counter: AtomicU32::new(0),
+            counter: AtomicU32::new(counter),
             num_waiters: AtomicUsize::new(0),
+            lock_futex: Mutex::new(LockFutex {
+                waiters: 0,
+                addr: 0,
+            }),
+            #[cfg(debug_assertions)]
+            lock_addr: AtomicUsize::new(0),
         }
     }
 
     /// Wait allows spurious wake-ups
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    pub fn wait<'a, G: Relock<'a>>(&self, guard: G) -> G {
+        self.wait_until(guard, None).0
+    }
+
+    /// Wait until `condition` returns `false`, handling spurious wake-ups.
+    pub fn wait_while<'a, G: Relock<'a> + DerefMut>(
+        &self,
+        mut guard: G,
+        mut condition: impl FnMut(&mut G::Target) -> bool,
+    ) -> G {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like `wait`, but gives up after `timeout`. Spurious wake-ups are still
+    /// possible.
+    pub fn wait_timeout<'a, G: Relock<'a>>(
+        &self,
+        guard: G,
+        timeout: Duration,
+    ) -> (G, WaitTimeoutResult) {
+        let (guard, woken) = self.wait_until(guard, Instant::now().checked_add(timeout));
+        (guard, WaitTimeoutResult(!woken))
+    }
+
+    /// Like `wait_while`, but gives up after `timeout`.
+    ///
+    /// Only reports a time out if the condition still holds.
+    pub fn wait_timeout_while<'a, G: Relock<'a> + DerefMut>(
+        &self,
+        mut guard: G,
+        timeout: Duration,
+        mut condition: impl FnMut(&mut G::Target) -> bool,
+    ) -> (G, WaitTimeoutResult) {
+        let deadline = Instant::now().checked_add(timeout);
+        while condition(&mut guard) {
+            let woken;
+            (guard, woken) = self.wait_until(guard, deadline);
+            if !woken {
+                let timed_out = condition(&mut guard);
+                return (guard, WaitTimeoutResult(timed_out));
+            }
+        }
+        (guard, WaitTimeoutResult(false))
+    }
+
+    /// Returns `false` along with the guard, if it timed out.
+    fn wait_until<'a, G: Relock<'a>>(&self, guard: G, deadline: Option<Instant>) -> (G, bool) {
         self.num_waiters.fetch_add(1, Relaxed);
 
         // Load the value before unlocking for an "atomic" behaviour.
         // This prevents losing a signal between unlocking the mutex and reading
         // the value (if we read this after unlocking).
-        let counter = self.counter.load(Relaxed);
+        let counter = self.observe();
 
-        let mutex = guard.mutex;
-        drop(guard);
+        let (lock, state) = guard.unlock();
+
+        #[cfg(debug_assertions)]
+        if !self.bind(lock) {
+            self.num_waiters.fetch_sub(1, Relaxed);
+            panic!("Condvar::wait called with a different lock than before");
+        }
+
+        // Register before going to sleep, so that `notify_all` doesn't move
+        // this thread anywhere else. Waiters whose lock has no futex can't be
+        // moved at all, so they skip the bookkeeping. Since a `Condvar` is
+        // only used with one lock, nobody registers then, and `notify_all`
+        // wakes them all up.
+        let futex = G::futex(lock);
+        if let Some(futex) = futex {
+            self.lock_futex.lock().register(futex.as_ptr() as usize);
+        }
 
         // Wait only if the counter hasn't changed since unlocking.
-        wait(&self.counter, counter);
+        let woken = futex::wait_until(&self.counter, counter, deadline);
 
+        // Whether we were woken up or not, we're no longer waiting.
+        if futex.is_some() {
+            self.lock_futex.lock().unregister();
+        }
         self.num_waiters.fetch_sub(1, Relaxed);
 
-        mutex.lock()
+        (G::relock_contended(lock, state), woken)
+    }
+
+    /// Remember `lock` on the first call. Returns `false` if a different lock
+    /// was used before.
+    #[cfg(debug_assertions)]
+    fn bind<L: ?Sized>(&self, lock: &L) -> bool {
+        let addr = (lock as *const L).cast::<()>() as usize;
+        match self.lock_addr.compare_exchange(0, addr, Relaxed, Relaxed) {
+            Ok(_) => true,
+            Err(bound) => bound == addr,
+        }
+    }
+
+    /// Load the counter, marking its value as seen by a waiter.
+    fn observe(&self) -> u32 {
+        self.counter.fetch_or(OBSERVED, Relaxed) | OBSERVED
+    }
+
+    /// Move the counter on, if a waiter has seen its current value. Returns the
+    /// new value.
+    ///
+    /// If no waiter has seen it, every waiter has either seen an older value
+    /// (and will be woken, or not go to sleep at all), or is yet to load it.
+    fn bump(&self) -> u32 {
+        match self.counter.fetch_update(Relaxed, Relaxed, |c| {
+            (c & OBSERVED != 0).then(|| (c & !OBSERVED).wrapping_add(2))
+        }) {
+            Ok(c) => (c & !OBSERVED).wrapping_add(2),
+            Err(c) => c,
+        }
     }
 
     // notify_one can actually "wake" more than one thread!
@@ -43,25 +206,87 @@ impl Condvar {
     // by `wake_one`, and both will be racing for the mutex.
     pub fn notify_one(&self) {
         if self.num_waiters.load(Relaxed) > 0 {
-            self.counter.fetch_add(1, Relaxed);
+            self.bump();
             wake_one(&self.counter);
         }
     }
 
     pub fn notify_all(&self) {
         if self.num_waiters.load(Relaxed) > 0 {
-            self.counter.fetch_add(1, Relaxed);
-            wake_all(&self.counter);
+            let counter = self.bump();
+            // Waking up everyone only has them fight over the lock. Wake one,
+            // and let the others be woken up by unlocking, one at a time.
+            //
+            // Holding `lock_futex` keeps the sleeping waiters registered, and
+            // so their lock borrowed (i.e. alive) until they've been moved.
+            let lock_futex = self.lock_futex.lock();
+            let futex = lock_futex.addr as *const AtomicU32;
+            if futex.is_null() || !futex::requeue(&self.counter, counter, futex) {
+                wake_all(&self.counter);
+            }
         }
     }
 }
 
+/// The waiters of a `Condvar`, and the futex of the lock they're using.
+///
+/// Waiters whose lock has a futex register before going to sleep, and
+/// unregister once they're awake. So such a sleeping waiter is always counted
+/// here.
+struct LockFutex {
+    waiters: usize,
+    /// Address of the `Relock::futex` shared by all the registered waiters. 0
+    /// if there's none, or if they aren't all waiting with the same lock.
+    /// Never 0 for a registered waiter.
+    addr: usize,
+}
+
+impl LockFutex {
+    fn register(&mut self, addr: usize) {
+        if self.waiters == 0 {
+            self.addr = addr;
+        } else if self.addr != addr {
+            // Only wake up a mix of waiters, never move them.
+            self.addr = 0;
+        }
+        self.waiters += 1;
+    }
+
+    fn unregister(&mut self) {
+        self.waiters -= 1;
+        if self.waiters == 0 {
+            // The lock may be gone once its waiters are.
+            self.addr = 0;
+        }
+    }
+}
+
+/// Returned by `Condvar::wait_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::{thread, time::Duration};
+    use std::{
+        sync::atomic::Ordering::*,
+        thread,
+        time::{Duration, Instant},
+    };
 
-    use super::Condvar;
-    use crate::mutex::Mutex;
+    use super::{Condvar, LockFutex};
+    use crate::{mutex::Mutex, rwlock::RwLock, spinlock::SpinLock};
 
     #[test]
     fn wait_non_zero() {
@@ -109,4 +334,266 @@ mod test {
         // while still allowing for a few spurious wake ups.
         assert!(wakeups < 10);
     }
+
+    #[test]
+    fn wait_while() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..10 {
+                    thread::sleep(Duration::from_millis(5));
+                    *mutex.lock() += 1;
+                    condvar.notify_one();
+                }
+            });
+            let m = condvar.wait_while(mutex.lock(), |m| *m < 10);
+            assert_eq!(*m, 10);
+        });
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        let start = Instant::now();
+        let (guard, result) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(50));
+        assert!(result.timed_out());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(condvar.num_waiters.load(Relaxed), 0);
+        drop(guard);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                *mutex.lock() = 1;
+                condvar.notify_one();
+            });
+            let (guard, result) =
+                condvar.wait_timeout_while(mutex.lock(), Duration::from_secs(10), |m| *m == 0);
+            assert!(!result.timed_out());
+            assert_eq!(*guard, 1);
+        });
+
+        let (guard, result) =
+            condvar.wait_timeout_while(mutex.lock(), Duration::from_millis(10), |m| *m == 1);
+        assert!(result.timed_out());
+        assert_eq!(*guard, 1);
+    }
+
+    #[test]
+    fn other_locks() {
+        let rwlock = RwLock::new(0);
+        let spinlock = SpinLock::new(0);
+        let (rwlock_changed, spinlock_changed) = (Condvar::new(), Condvar::new());
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                *rwlock.write() = 1;
+                rwlock_changed.notify_all();
+                thread::sleep(Duration::from_millis(10));
+                *spinlock.lock() = 1;
+                spinlock_changed.notify_all();
+            });
+            let guard = rwlock_changed.wait_while(rwlock.write(), |v| *v == 0);
+            assert_eq!(*guard, 1);
+            drop(guard);
+            let guard = spinlock_changed.wait_while(spinlock.lock(), |v| *v == 0);
+            assert_eq!(*guard, 1);
+        });
+    }
+
+    #[test]
+    fn no_futex_no_bookkeeping() {
+        let spinlock = SpinLock::new(0);
+        let condvar = Condvar::new();
+        thread::scope(|s| {
+            s.spawn(|| drop(condvar.wait_while(spinlock.lock(), |v| *v == 0)));
+            while condvar.num_waiters.load(Relaxed) == 0 {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+            // A spin lock has no futex to move the waiter to.
+            assert_eq!(condvar.lock_futex.lock().waiters, 0);
+            *spinlock.lock() = 1;
+            condvar.notify_all();
+        });
+    }
+
+    #[test]
+    fn wait_keeps_poisoning() {
+        let poisoning = Mutex::new_poisoning(0);
+        let plain = Mutex::new(0);
+        thread::scope(|s| {
+            for mutex in [&poisoning, &plain] {
+                let t = s.spawn(|| {
+                    let condvar = Condvar::new();
+                    let guard = mutex.lock();
+                    let (_guard, _) = condvar.wait_timeout(guard, Duration::from_millis(1));
+                    panic!("oops");
+                });
+                assert!(t.join().is_err());
+            }
+        });
+        // The guards come back from the wait poisoning the mutex or not, like
+        // they did before.
+        assert!(poisoning.is_poisoned());
+        assert!(!plain.is_poisoned());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "different lock")]
+    fn different_locks() {
+        let a = Mutex::new(0);
+        let b = Mutex::new(0);
+        let condvar = Condvar::new();
+        let _ = condvar.wait_timeout(a.lock(), Duration::from_millis(1));
+        let _ = condvar.wait_timeout(a.lock(), Duration::from_millis(1));
+        let _ = condvar.wait_timeout(b.lock(), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn notify_all_requeues() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+        thread::scope(|s| {
+            for _ in 0..20 {
+                s.spawn(|| {
+                    let mut guard = condvar.wait_while(mutex.lock(), |v| *v == 0);
+                    *guard += 1;
+                });
+            }
+            while condvar.num_waiters.load(Relaxed) < 20 {
+                thread::yield_now();
+            }
+            // Give them a moment to actually go to sleep.
+            thread::sleep(Duration::from_millis(10));
+            *mutex.lock() = 1;
+            condvar.notify_all();
+        });
+        assert_eq!(*mutex.lock(), 21);
+        assert!(!mutex.is_locked());
+    }
+
+    // The tests below walk through the steps of `wait` by hand:
+    #[test]
+    fn lock_futex() {
+        let mut lock_futex = LockFutex {
+            waiters: 0,
+            addr: 0,
+        };
+        lock_futex.register(8);
+        lock_futex.register(8);
+        assert_eq!(lock_futex.addr, 8);
+
+        // A waiter with another lock stops the others from being moved.
+        lock_futex.register(16);
+        assert_eq!(lock_futex.addr, 0);
+        lock_futex.unregister();
+        lock_futex.register(8);
+        assert_eq!(lock_futex.addr, 0);
+
+        // Until they're all gone. Their lock may be gone too, by now.
+        for _ in 0..3 {
+            lock_futex.unregister();
+        }
+        assert_eq!(lock_futex.addr, 0);
+        lock_futex.register(16);
+        assert_eq!(lock_futex.addr, 16);
+    }
+
+    // `prepare` is everything before unlocking, and `would_sleep` is the check
+    // the futex makes before putting the thread to sleep.
+
+    fn prepare(condvar: &Condvar) -> u32 {
+        condvar.num_waiters.fetch_add(1, Relaxed);
+        condvar.observe()
+    }
+
+    fn would_sleep(condvar: &Condvar, counter: u32) -> bool {
+        condvar.counter.load(Relaxed) == counter
+    }
+
+    #[test]
+    fn notify_before_sleeping() {
+        let condvar = Condvar::new();
+        let counter = prepare(&condvar);
+        assert!(would_sleep(&condvar, counter));
+        condvar.notify_one();
+        assert!(!would_sleep(&condvar, counter));
+    }
+
+    #[test]
+    fn notify_before_waiting() {
+        let condvar = Condvar::new();
+        // Nobody is waiting, so this is a no-op...
+        condvar.notify_all();
+        // ...and doesn't affect later waits.
+        let counter = prepare(&condvar);
+        assert!(would_sleep(&condvar, counter));
+    }
+
+    #[test]
+    fn notify_two_waiters() {
+        let condvar = Condvar::new();
+        let first = prepare(&condvar);
+        let second = prepare(&condvar);
+        condvar.notify_one();
+        assert!(!would_sleep(&condvar, first));
+        assert!(!would_sleep(&condvar, second));
+
+        // A waiter arriving after the notification sees the new value.
+        let third = prepare(&condvar);
+        assert!(would_sleep(&condvar, third));
+        condvar.notify_one();
+        assert!(!would_sleep(&condvar, third));
+        assert!(!would_sleep(&condvar, first));
+    }
+
+    #[test]
+    fn counter_wraps() {
+        for seed in [u32::MAX - 1, u32::MAX] {
+            let condvar = Condvar::with_counter(seed);
+            let counter = prepare(&condvar);
+            assert_eq!(counter, u32::MAX);
+            condvar.notify_one();
+            assert_eq!(condvar.counter.load(Relaxed), 0);
+            assert!(!would_sleep(&condvar, counter));
+        }
+    }
+
+    #[test]
+    fn notifications_dont_wrap_around() {
+        let condvar = Condvar::with_counter(u32::MAX - 3);
+        let counter = prepare(&condvar);
+        // Without waiters seeing the new values, notifications don't move the
+        // counter back towards `counter`.
+        for _ in 0..1000 {
+            condvar.notify_one();
+            condvar.notify_all();
+            assert!(!would_sleep(&condvar, counter));
+        }
+        assert_eq!(condvar.counter.load(Relaxed), u32::MAX - 1);
+    }
+
+    #[test]
+    fn wait_across_wrap() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::with_counter(u32::MAX - 1);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 1..=5 {
+                    thread::sleep(Duration::from_millis(5));
+                    *mutex.lock() = i;
+                    condvar.notify_all();
+                }
+            });
+            for i in 1..=5 {
+                let guard = condvar.wait_while(mutex.lock(), |v| *v < i);
+                assert!(*guard >= i);
+            }
+        });
+    }
 }
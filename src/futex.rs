@@ -0,0 +1,107 @@
+//! Futex operations that `atomic_wait` doesn't provide.
+use std::{
+    sync::atomic::AtomicU32,
+    time::{Duration, Instant},
+};
+
+/// Like `atomic_wait::wait`, but gives up once the `deadline` (if any) has
+/// passed.
+///
+/// Returns `false` if it timed out. Just like `wait`, this can return early
+/// for no reason at all, so the caller has to check the value again.
+pub fn wait_until(atomic: &AtomicU32, value: u32, deadline: Option<Instant>) -> bool {
+    let Some(deadline) = deadline else {
+        atomic_wait::wait(atomic, value);
+        return true;
+    };
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(timeout) if !timeout.is_zero() => wait_timeout(atomic, value, timeout),
+        _ => false,
+    }
+}
+
+/// Like `atomic_wait::wake_one`, but returns whether a thread was actually
+/// woken up.
+///
+/// Other platforms can't tell, so they always report `false`.
+#[cfg(target_os = "linux")]
+pub fn wake_one(atomic: &AtomicU32) -> bool {
+    let woken = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            atomic as *const AtomicU32,
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+        )
+    };
+    woken > 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn wake_one(atomic: &AtomicU32) -> bool {
+    atomic_wait::wake_one(atomic);
+    false
+}
+
+/// Wake one thread waiting on `from`, and move the others over to wait on
+/// `to`, without waking them up. Does nothing if `from` no longer holds
+/// `value`.
+///
+/// Returns `false` if `from` didn't hold `value`. Other platforms can't move
+/// waiters, so they wake all of them instead.
+#[cfg(target_os = "linux")]
+pub fn requeue(from: &AtomicU32, value: u32, to: *const AtomicU32) -> bool {
+    // The number of threads to move is passed in place of the timeout.
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            from as *const AtomicU32,
+            libc::FUTEX_CMP_REQUEUE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+            i32::MAX as usize,
+            to,
+            value,
+        )
+    };
+    r >= 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn requeue(from: &AtomicU32, _value: u32, _to: *const AtomicU32) -> bool {
+    atomic_wait::wake_all(from);
+    true
+}
+
+#[cfg(target_os = "linux")]
+fn wait_timeout(atomic: &AtomicU32, value: u32, timeout: Duration) -> bool {
+    // FUTEX_WAIT takes a relative timeout.
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            atomic as *const AtomicU32,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            value,
+            &timeout as *const libc::timespec,
+        )
+    };
+    !(r < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait_timeout(atomic: &AtomicU32, value: u32, timeout: Duration) -> bool {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    // There's no portable way to wait on an address with a timeout, so poll.
+    let deadline = Instant::now() + timeout;
+    while atomic.load(Relaxed) == value {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::yield_now();
+    }
+    true
+}
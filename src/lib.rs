@@ -1,9 +1,16 @@
 pub mod arc;
 pub mod channel1;
 pub mod channel2;
+pub mod clh_lock;
 pub mod condvar;
+mod futex;
+pub mod lock;
+pub mod mcs_lock;
 pub mod mutex;
 pub mod once_data;
+pub mod poison;
 pub mod processor;
+pub mod reentrant_mutex;
 pub mod rwlock;
 pub mod spinlock;
+pub mod ticket_lock;
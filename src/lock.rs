@@ -0,0 +1,1089 @@
+//! Traits for the locks without their data (`mutex::RawMutex`,
+//! `spinlock::RawSpinLock`, ...), and `Lock` / `RwLock` to put data behind any
+//! of them. `mutex::Mutex`, `spinlock::SpinLock`, `ticket_lock::TicketLock`
+//! and `rwlock::RwLock` are all instances of these, so they share one
+//! interface. Code that takes the raw lock as a type parameter can switch
+//! between the implementations, e.g. for benchmarking.
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::AtomicU32,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    arc::Arc,
+    condvar::Relock,
+    poison::{self, LockResult},
+};
+
+/// A lock that only one thread can hold at a time.
+///
+/// # Safety
+///
+/// Once `lock` returns, no other thread may acquire the lock until `unlock` is
+/// called. `Lock` relies on this to give out `&mut T`.
+///
+/// Any thread may unlock the lock, not just the one that locked it: an
+/// `ArcLockGuard` can be sent to another thread.
+pub unsafe trait RawLock {
+    /// An unlocked lock, so that `Lock::new` can be `const`.
+    const INIT: Self;
+
+    fn lock(&self);
+
+    /// # Safety
+    ///
+    /// The lock must be held by the caller, i.e. acquired in this context.
+    unsafe fn unlock(&self);
+
+    /// Whether any thread holds the lock. It may have changed by the time
+    /// this returns.
+    fn is_locked(&self) -> bool;
+
+    /// The futex word that threads waiting for the lock sleep on, if any.
+    ///
+    /// `Condvar::notify_all` moves its waiters over to it, instead of waking
+    /// them all up at once.
+    fn futex(&self) -> Option<&AtomicU32> {
+        None
+    }
+
+    /// Lock, after possibly being moved over to `futex` by a `Condvar`.
+    ///
+    /// This must leave the lock marked as contended, so that unlocking wakes
+    /// up the next waiter that was moved over.
+    fn lock_requeued(&self) {
+        self.lock();
+    }
+}
+
+/// # Safety
+///
+/// `try_lock` returning `true` counts as acquiring the lock.
+pub unsafe trait RawTryLock: RawLock {
+    /// Acquire the lock only if it's free right now.
+    fn try_lock(&self) -> bool;
+}
+
+/// # Safety
+///
+/// `try_lock_until` returning `true` counts as acquiring the lock.
+pub unsafe trait RawTimedLock: RawTryLock {
+    /// Returns `false` if the lock couldn't be acquired by `deadline`.
+    fn try_lock_until(&self, deadline: Instant) -> bool;
+
+    /// Returns `false` if the lock couldn't be acquired within `timeout`.
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            // Too far in the future to ever time out.
+            None => {
+                self.lock();
+                true
+            }
+        }
+    }
+}
+
+/// A lock that can be handed directly over to a waiting thread.
+///
+/// # Safety
+///
+/// `unlock_fair` has to release the lock, like `unlock`.
+pub unsafe trait RawFairLock: RawLock {
+    /// # Safety
+    ///
+    /// The lock must be held by the caller.
+    unsafe fn unlock_fair(&self);
+}
+
+/// A lock that many readers, or a single writer, can hold at a time.
+///
+/// # Safety
+///
+/// While a thread holds the write lock, no other thread may acquire a read or
+/// write lock. `RwLock` relies on this to give out `&mut T`.
+///
+/// Like with `RawLock`, any thread may unlock the lock.
+pub unsafe trait RawRwLock {
+    /// An unlocked lock, so that `RwLock::new` can be `const`.
+    const INIT: Self;
+
+    fn read(&self);
+
+    /// Acquire a read lock only if it's available right now.
+    fn try_read(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// A read lock must be held by the caller.
+    unsafe fn read_unlock(&self);
+
+    fn write(&self);
+
+    /// Acquire the write lock only if it's available right now.
+    fn try_write(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// The write lock must be held by the caller.
+    unsafe fn write_unlock(&self);
+}
+
+/// # Safety
+///
+/// `try_read_until` and `try_write_until` returning `true` count as acquiring
+/// the lock.
+pub unsafe trait RawTimedRwLock: RawRwLock {
+    /// Returns `false` if a read lock couldn't be acquired by `deadline`.
+    fn try_read_until(&self, deadline: Instant) -> bool;
+
+    /// Returns `false` if the write lock couldn't be acquired by `deadline`.
+    fn try_write_until(&self, deadline: Instant) -> bool;
+
+    fn try_read_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_read_until(deadline),
+            // Too far in the future to ever time out.
+            None => {
+                self.read();
+                true
+            }
+        }
+    }
+
+    fn try_write_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_write_until(deadline),
+            None => {
+                self.write();
+                true
+            }
+        }
+    }
+}
+
+/// A lock with upgradable reads: read locks that can later be turned into the
+/// write lock, without letting any other writer in between.
+///
+/// # Safety
+///
+/// An upgradable read lock coexists with plain read locks, but not with
+/// another upgradable one, nor with the write lock. Once `upgrade` returns
+/// (or `try_upgrade` returns `true`), the caller holds the write lock
+/// instead.
+pub unsafe trait RawUpgradableRwLock: RawRwLock {
+    fn upgradable_read(&self);
+
+    /// Acquire an upgradable read lock only if it's available right now.
+    fn try_upgradable_read(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// An upgradable read lock must be held by the caller.
+    unsafe fn upgradable_unlock(&self);
+
+    /// Wait for the other readers to leave, and take the write lock.
+    ///
+    /// # Safety
+    ///
+    /// An upgradable read lock must be held by the caller.
+    unsafe fn upgrade(&self);
+
+    /// Take the write lock only if there are no other readers.
+    ///
+    /// # Safety
+    ///
+    /// An upgradable read lock must be held by the caller.
+    unsafe fn try_upgrade(&self) -> bool;
+}
+
+/// # Safety
+///
+/// `downgrade` turns the write lock into a read lock, without letting any
+/// writer in between.
+pub unsafe trait RawDowngradableRwLock: RawRwLock {
+    /// # Safety
+    ///
+    /// The write lock must be held by the caller.
+    unsafe fn downgrade(&self);
+}
+
+/// Data behind any `RawLock`.
+///
+/// A lock created with `new_poisoning` is poisoned, like `std::sync::Mutex`,
+/// if a thread panics while holding it, whichever way it was locked.
+/// `lock_checked` reports it, while `lock` (and the `try_lock*` variants)
+/// ignore it. A lock created with `new` never gets poisoned, so its guards
+/// don't even check whether the thread is panicking, to keep the hot paths
+/// cheap.
+pub struct Lock<R, T> {
+    raw: R,
+    poison: poison::Flag,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<R: RawLock + Sync, T> Sync for Lock<R, T> where T: Send {}
+
+impl<R: RawLock, T> Lock<R, T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            raw: R::INIT,
+            poison: poison::Flag::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// A lock that gets poisoned if a thread panics while holding it.
+    pub const fn new_poisoning(data: T) -> Self {
+        Self {
+            raw: R::INIT,
+            poison: poison::Flag::new(true),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> LockGuard<'_, R, T> {
+        self.raw.lock();
+        LockGuard::new(self, self.poison.guard())
+    }
+
+    /// Like `lock`, but returns an error if another thread panicked while
+    /// holding the lock.
+    pub fn lock_checked(&self) -> LockResult<LockGuard<'_, R, T>> {
+        poison::check(&self.poison, self.lock())
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Mark the data as recovered after a panic.
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Whether any thread holds the lock. It may have changed by the time
+    /// this returns.
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Like `lock`, but the guard keeps the lock alive through a clone of the
+    /// `Arc`, instead of borrowing it. So it can be stored anywhere, or sent
+    /// to another thread.
+    ///
+    // Called as `Mutex::lock_arc(&mutex)`, since `self: &Arc<Self>` only works
+    // for the standard library's `Arc`.
+    pub fn lock_arc(this: &Arc<Self>) -> ArcLockGuard<R, T> {
+        this.raw.lock();
+        ArcLockGuard {
+            poison: this.poison.guard(),
+            lock: Arc::clone(this),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Mutable access to the data, without locking: the borrow checker
+    /// already guarantees that nobody else has access to the lock.
+    ///
+    /// Like `lock`, this ignores poisoning.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Like `lock`, this ignores poisoning.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn raw(&self) -> &R {
+        &self.raw
+    }
+}
+
+impl<R: RawTryLock, T> Lock<R, T> {
+    /// Acquire the lock only if it's not held by any other thread.
+    pub fn try_lock(&self) -> Option<LockGuard<'_, R, T>> {
+        self.raw
+            .try_lock()
+            .then(|| LockGuard::new(self, self.poison.guard()))
+    }
+}
+
+impl<R: RawTimedLock, T> Lock<R, T> {
+    /// Like `lock`, but gives up if the lock couldn't be acquired within
+    /// `timeout`.
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<LockGuard<'_, R, T>> {
+        self.raw
+            .try_lock_for(timeout)
+            .then(|| LockGuard::new(self, self.poison.guard()))
+    }
+
+    /// Like `lock`, but gives up if the lock couldn't be acquired by
+    /// `deadline`.
+    pub fn try_lock_until(&self, deadline: Instant) -> Option<LockGuard<'_, R, T>> {
+        self.raw
+            .try_lock_until(deadline)
+            .then(|| LockGuard::new(self, self.poison.guard()))
+    }
+}
+
+pub struct LockGuard<'a, R: RawLock, T> {
+    lock: &'a Lock<R, T>,
+    poison: poison::Guard,
+    /// `&Lock<R, T>` alone would make the guard `Sync` whenever `T` is `Send`.
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, R: RawLock + Sync, T: Sync> Sync for LockGuard<'a, R, T> {}
+
+impl<'a, R: RawLock, T> LockGuard<'a, R, T> {
+    fn new(lock: &'a Lock<R, T>, poison: poison::Guard) -> Self {
+        Self {
+            lock,
+            poison,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Narrow the guard down to a part of the data, e.g. one of its fields.
+    /// The lock stays locked until the returned guard is dropped.
+    ///
+    // Called as `LockGuard::map(guard, f)`, so that `guard.map(f)` still
+    // resolves to `T::map`.
+    pub fn map<U: ?Sized>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedLockGuard<'a, R, U> {
+        // If `f` panics, `guard` is dropped normally, unlocking the lock.
+        let data = NonNull::from(f(unsafe { &mut *guard.lock.data.get() }));
+        guard.into_mapped(data)
+    }
+
+    /// Like `map`, but gives the guard back if `f` returns `None`.
+    pub fn try_map<U: ?Sized>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedLockGuard<'a, R, U>, Self> {
+        match f(unsafe { &mut *guard.lock.data.get() }) {
+            Some(data) => Ok(guard.into_mapped(NonNull::from(data))),
+            None => Err(guard),
+        }
+    }
+
+    fn into_mapped<U: ?Sized>(self, data: NonNull<U>) -> MappedLockGuard<'a, R, U> {
+        // The mapped guard takes over unlocking.
+        let this = ManuallyDrop::new(self);
+        MappedLockGuard {
+            raw: &this.lock.raw,
+            poison_flag: &this.lock.poison,
+            poison: this.poison,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, R: RawFairLock, T> LockGuard<'a, R, T> {
+    /// Unlock, handing the lock over to a waiting thread (if any).
+    ///
+    /// Dropping the guard lets a newly arriving thread grab the lock before
+    /// the waiter woken up for it gets to run, which can starve the waiters
+    /// under heavy load.
+    pub fn unlock_fair(guard: Self) {
+        let lock = guard.lock;
+        lock.poison.done(&guard.poison);
+        mem::forget(guard);
+        // Safety: The guard was created by locking.
+        unsafe { lock.raw.unlock_fair() };
+    }
+}
+
+impl<'a, R: RawLock, T> Deref for LockGuard<'a, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The existence of this Guard instance guarantees this is the
+        // exclusive reference to the data.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, R: RawLock, T> DerefMut for LockGuard<'a, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, R: RawLock, T> Drop for LockGuard<'a, R, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+        // Safety: The guard was created by locking.
+        unsafe { self.lock.raw.unlock() };
+    }
+}
+
+impl<'a, R: RawLock, T> Relock<'a> for LockGuard<'a, R, T> {
+    type Lock = Lock<R, T>;
+    // Whether the guard poisons the lock was decided when it was first
+    // locked, and a wait doesn't change it.
+    type State = poison::Guard;
+
+    fn unlock(self) -> (&'a Lock<R, T>, poison::Guard) {
+        let (lock, poison) = (self.lock, self.poison);
+        drop(self);
+        (lock, poison)
+    }
+
+    fn relock(lock: &'a Lock<R, T>, poison: poison::Guard) -> Self {
+        lock.raw.lock();
+        LockGuard::new(lock, poison)
+    }
+
+    fn futex(lock: &Lock<R, T>) -> Option<&AtomicU32> {
+        lock.raw.futex()
+    }
+
+    fn relock_contended(lock: &'a Lock<R, T>, poison: poison::Guard) -> Self {
+        lock.raw.lock_requeued();
+        LockGuard::new(lock, poison)
+    }
+}
+
+/// An owned `LockGuard`, created by `Lock::lock_arc`.
+pub struct ArcLockGuard<R: RawLock, T> {
+    lock: Arc<Lock<R, T>>,
+    poison: poison::Guard,
+    /// Opts out of the auto traits: `Arc<Lock<R, T>>` alone would make the
+    /// guard `Sync` even if `T` isn't.
+    _marker: PhantomData<*const ()>,
+}
+
+// The guard can unlock the lock from any thread.
+unsafe impl<R: RawLock + Send + Sync, T: Send> Send for ArcLockGuard<R, T> {}
+// Sharing the guard shares `&T`.
+unsafe impl<R: RawLock + Sync, T: Sync> Sync for ArcLockGuard<R, T> {}
+
+impl<R: RawLock, T> Deref for ArcLockGuard<R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<R: RawLock, T> DerefMut for ArcLockGuard<R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<R: RawLock, T> Drop for ArcLockGuard<R, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+        // Safety: The guard was created by locking. The `Arc` is dropped right
+        // after this, so the lock is still around.
+        unsafe { self.lock.raw.unlock() };
+    }
+}
+
+/// A `LockGuard` for a part of the data, created by `LockGuard::map`.
+pub struct MappedLockGuard<'a, R: RawLock, T: ?Sized> {
+    raw: &'a R,
+    poison_flag: &'a poison::Flag,
+    poison: poison::Guard,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, R: RawLock + Sync, T: ?Sized + Sync> Sync for MappedLockGuard<'a, R, T> {}
+
+impl<'a, R: RawLock, T: ?Sized> MappedLockGuard<'a, R, T> {
+    /// Narrow the guard down even further.
+    pub fn map<U: ?Sized>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedLockGuard<'a, R, U> {
+        let data = NonNull::from(f(unsafe { &mut *guard.data.as_ptr() }));
+        let this = ManuallyDrop::new(guard);
+        MappedLockGuard {
+            raw: this.raw,
+            poison_flag: this.poison_flag,
+            poison: this.poison,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, R: RawLock, T: ?Sized> Deref for MappedLockGuard<'a, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The lock is still locked, and the data was borrowed from it.
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<'a, R: RawLock, T: ?Sized> DerefMut for MappedLockGuard<'a, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.data.as_mut() }
+    }
+}
+
+impl<'a, R: RawLock, T: ?Sized> Drop for MappedLockGuard<'a, R, T> {
+    fn drop(&mut self) {
+        self.poison_flag.done(&self.poison);
+        // Safety: The guard was created by locking.
+        unsafe { self.raw.unlock() };
+    }
+}
+
+/// Data behind any `RawRwLock`.
+///
+/// Like `Lock`, one created with `new_poisoning` is poisoned by a thread that
+/// panics while holding a write guard. Only `read_checked` and
+/// `write_checked` report it.
+pub struct RwLock<R, T> {
+    raw: R,
+    poison: poison::Flag,
+    data: UnsafeCell<T>,
+}
+
+// Readers on different threads share the data, so it has to be `Sync` too.
+unsafe impl<R: RawRwLock + Sync, T> Sync for RwLock<R, T> where T: Send + Sync {}
+
+impl<R: RawRwLock, T> RwLock<R, T> {
+    pub const fn new(data: T) -> Self {
+        Self::from_raw(R::INIT, data)
+    }
+
+    /// A lock that gets poisoned if a thread panics while holding the write
+    /// lock.
+    pub const fn new_poisoning(data: T) -> Self {
+        Self {
+            raw: R::INIT,
+            poison: poison::Flag::new(true),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// For raw locks that can be set up differently than `R::INIT`. The lock
+    /// doesn't get poisoned.
+    pub const fn from_raw(raw: R, data: T) -> Self {
+        Self {
+            raw,
+            poison: poison::Flag::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, R, T> {
+        self.raw.read();
+        ReadGuard { lock: self }
+    }
+
+    /// Acquire a read lock only if it's available right away.
+    pub fn try_read(&self) -> Option<ReadGuard<'_, R, T>> {
+        self.raw.try_read().then(|| ReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, R, T> {
+        self.raw.write();
+        WriteGuard::new(self, self.poison.guard())
+    }
+
+    /// Acquire the write lock only if it's available right away.
+    pub fn try_write(&self) -> Option<WriteGuard<'_, R, T>> {
+        self.raw
+            .try_write()
+            .then(|| WriteGuard::new(self, self.poison.guard()))
+    }
+
+    /// Like `read`, but returns an error if the lock is poisoned.
+    pub fn read_checked(&self) -> LockResult<ReadGuard<'_, R, T>> {
+        poison::check(&self.poison, self.read())
+    }
+
+    /// Like `write`, but returns an error if the lock is poisoned.
+    pub fn write_checked(&self) -> LockResult<WriteGuard<'_, R, T>> {
+        poison::check(&self.poison, self.write())
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Mark the data as recovered after a panic.
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Like `read`, but the guard keeps the lock alive through a clone of the
+    /// `Arc`, instead of borrowing it.
+    pub fn read_arc(this: &Arc<Self>) -> ArcReadGuard<R, T> {
+        this.raw.read();
+        ArcReadGuard {
+            lock: Arc::clone(this),
+        }
+    }
+
+    /// Like `write`, but the guard keeps the lock alive through a clone of
+    /// the `Arc`, instead of borrowing it.
+    pub fn write_arc(this: &Arc<Self>) -> ArcWriteGuard<R, T> {
+        this.raw.write();
+        ArcWriteGuard {
+            poison: this.poison.guard(),
+            lock: Arc::clone(this),
+        }
+    }
+
+    /// Mutable access to the data, without locking: the borrow checker
+    /// already guarantees that nobody else has access to the lock.
+    ///
+    /// Like `write`, this ignores poisoning.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Like `write`, this ignores poisoning.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<R: RawTimedRwLock, T> RwLock<R, T> {
+    /// Like `read`, but gives up if the lock couldn't be acquired within
+    /// `timeout`.
+    pub fn try_read_for(&self, timeout: Duration) -> Option<ReadGuard<'_, R, T>> {
+        self.raw
+            .try_read_for(timeout)
+            .then(|| ReadGuard { lock: self })
+    }
+
+    /// Like `read`, but gives up if the lock couldn't be acquired by
+    /// `deadline`.
+    pub fn try_read_until(&self, deadline: Instant) -> Option<ReadGuard<'_, R, T>> {
+        self.raw
+            .try_read_until(deadline)
+            .then(|| ReadGuard { lock: self })
+    }
+
+    /// Like `write`, but gives up if the lock couldn't be acquired within
+    /// `timeout`.
+    pub fn try_write_for(&self, timeout: Duration) -> Option<WriteGuard<'_, R, T>> {
+        self.raw
+            .try_write_for(timeout)
+            .then(|| WriteGuard::new(self, self.poison.guard()))
+    }
+
+    /// Like `write`, but gives up if the lock couldn't be acquired by
+    /// `deadline`.
+    pub fn try_write_until(&self, deadline: Instant) -> Option<WriteGuard<'_, R, T>> {
+        self.raw
+            .try_write_until(deadline)
+            .then(|| WriteGuard::new(self, self.poison.guard()))
+    }
+}
+
+impl<R: RawUpgradableRwLock, T> RwLock<R, T> {
+    /// A read lock that can later be upgraded to a write lock, without
+    /// letting any other writer in between.
+    ///
+    /// It coexists with plain readers, but not with other upgradable readers.
+    pub fn upgradable_read(&self) -> UpgradableGuard<'_, R, T> {
+        self.raw.upgradable_read();
+        UpgradableGuard { lock: self }
+    }
+
+    /// Acquire an upgradable read lock only if it's available right away.
+    pub fn try_upgradable_read(&self) -> Option<UpgradableGuard<'_, R, T>> {
+        self.raw
+            .try_upgradable_read()
+            .then(|| UpgradableGuard { lock: self })
+    }
+}
+
+pub struct ReadGuard<'a, R: RawRwLock, T> {
+    lock: &'a RwLock<R, T>,
+}
+
+impl<'a, R: RawRwLock, T> ReadGuard<'a, R, T> {
+    /// Narrow the guard down to a part of the data, e.g. one of its fields.
+    /// The lock stays read-locked until the returned guard is dropped.
+    pub fn map<U: ?Sized>(guard: Self, f: impl FnOnce(&T) -> &U) -> MappedReadGuard<'a, R, U> {
+        let data = NonNull::from(f(unsafe { &*guard.lock.data.get() }));
+        let this = ManuallyDrop::new(guard);
+        MappedReadGuard {
+            raw: &this.lock.raw,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, R: RawRwLock, T> Deref for ReadGuard<'a, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, R: RawRwLock, T> Drop for ReadGuard<'a, R, T> {
+    fn drop(&mut self) {
+        // Safety: The guard was created by read-locking.
+        unsafe { self.lock.raw.read_unlock() };
+    }
+}
+
+pub struct UpgradableGuard<'a, R: RawUpgradableRwLock, T> {
+    lock: &'a RwLock<R, T>,
+}
+
+impl<'a, R: RawUpgradableRwLock, T> UpgradableGuard<'a, R, T> {
+    /// Wait for the other readers to leave, and turn this into a write lock.
+    ///
+    /// This deadlocks if the same thread still holds a plain read guard.
+    ///
+    // Called as `UpgradableGuard::upgrade(guard)`, like `Arc::get_mut`, so
+    // that it can't be confused with an `upgrade` method on `T`.
+    pub fn upgrade(guard: Self) -> WriteGuard<'a, R, T> {
+        let lock = guard.lock;
+        mem::forget(guard);
+        // Safety: The guard was created by an upgradable read lock.
+        unsafe { lock.raw.upgrade() };
+        WriteGuard::new(lock, lock.poison.guard())
+    }
+
+    /// Upgrade only if there are no other readers, giving the guard back
+    /// otherwise.
+    pub fn try_upgrade(guard: Self) -> Result<WriteGuard<'a, R, T>, Self> {
+        if !unsafe { guard.lock.raw.try_upgrade() } {
+            return Err(guard);
+        }
+        let lock = guard.lock;
+        mem::forget(guard);
+        Ok(WriteGuard::new(lock, lock.poison.guard()))
+    }
+}
+
+impl<'a, R: RawUpgradableRwLock, T> Deref for UpgradableGuard<'a, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, R: RawUpgradableRwLock, T> Drop for UpgradableGuard<'a, R, T> {
+    fn drop(&mut self) {
+        // Safety: The guard was created by an upgradable read lock.
+        unsafe { self.lock.raw.upgradable_unlock() };
+    }
+}
+
+pub struct WriteGuard<'a, R: RawRwLock, T> {
+    lock: &'a RwLock<R, T>,
+    poison: poison::Guard,
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, R: RawRwLock + Sync, T: Sync> Sync for WriteGuard<'a, R, T> {}
+
+impl<'a, R: RawRwLock, T> WriteGuard<'a, R, T> {
+    fn new(lock: &'a RwLock<R, T>, poison: poison::Guard) -> Self {
+        Self {
+            lock,
+            poison,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Narrow the guard down to a part of the data, e.g. one of its fields.
+    /// The lock stays write-locked until the returned guard is dropped.
+    pub fn map<U: ?Sized>(
+        guard: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedWriteGuard<'a, R, U> {
+        // If `f` panics, `guard` is dropped normally, unlocking the lock.
+        let data = NonNull::from(f(unsafe { &mut *guard.lock.data.get() }));
+        let this = ManuallyDrop::new(guard);
+        MappedWriteGuard {
+            raw: &this.lock.raw,
+            poison_flag: &this.lock.poison,
+            poison: this.poison,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, R: RawDowngradableRwLock, T> WriteGuard<'a, R, T> {
+    /// Turn this into a read lock, without letting any writer in between.
+    pub fn downgrade(guard: Self) -> ReadGuard<'a, R, T> {
+        let lock = guard.lock;
+        lock.poison.done(&guard.poison);
+        mem::forget(guard);
+        // Safety: The guard was created by write-locking.
+        unsafe { lock.raw.downgrade() };
+        ReadGuard { lock }
+    }
+}
+
+impl<'a, R: RawRwLock, T> Deref for WriteGuard<'a, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, R: RawRwLock, T> DerefMut for WriteGuard<'a, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, R: RawRwLock, T> Drop for WriteGuard<'a, R, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+        // Safety: The guard was created by write-locking.
+        unsafe { self.lock.raw.write_unlock() };
+    }
+}
+
+impl<'a, R: RawRwLock, T> Relock<'a> for WriteGuard<'a, R, T> {
+    type Lock = RwLock<R, T>;
+    type State = poison::Guard;
+
+    fn unlock(self) -> (&'a RwLock<R, T>, poison::Guard) {
+        let (lock, poison) = (self.lock, self.poison);
+        drop(self);
+        (lock, poison)
+    }
+
+    fn relock(lock: &'a RwLock<R, T>, poison: poison::Guard) -> Self {
+        lock.raw.write();
+        WriteGuard::new(lock, poison)
+    }
+}
+
+/// An owned `ReadGuard`, created by `RwLock::read_arc`.
+pub struct ArcReadGuard<R: RawRwLock, T> {
+    lock: Arc<RwLock<R, T>>,
+}
+
+impl<R: RawRwLock, T> Deref for ArcReadGuard<R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<R: RawRwLock, T> Drop for ArcReadGuard<R, T> {
+    fn drop(&mut self) {
+        // Safety: The guard was created by read-locking.
+        unsafe { self.lock.raw.read_unlock() };
+    }
+}
+
+/// An owned `WriteGuard`, created by `RwLock::write_arc`.
+pub struct ArcWriteGuard<R: RawRwLock, T> {
+    lock: Arc<RwLock<R, T>>,
+    poison: poison::Guard,
+}
+
+impl<R: RawRwLock, T> Deref for ArcWriteGuard<R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<R: RawRwLock, T> DerefMut for ArcWriteGuard<R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<R: RawRwLock, T> Drop for ArcWriteGuard<R, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+        // Safety: The guard was created by write-locking.
+        unsafe { self.lock.raw.write_unlock() };
+    }
+}
+
+/// A `ReadGuard` for a part of the data, created by `ReadGuard::map`.
+pub struct MappedReadGuard<'a, R: RawRwLock, T: ?Sized> {
+    raw: &'a R,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+unsafe impl<'a, R: RawRwLock + Sync, T: ?Sized + Sync> Sync for MappedReadGuard<'a, R, T> {}
+
+impl<'a, R: RawRwLock, T: ?Sized> Deref for MappedReadGuard<'a, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The lock is still read-locked, and the data was borrowed
+        // from it.
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<'a, R: RawRwLock, T: ?Sized> Drop for MappedReadGuard<'a, R, T> {
+    fn drop(&mut self) {
+        // Safety: The guard was created by read-locking.
+        unsafe { self.raw.read_unlock() };
+    }
+}
+
+/// A `WriteGuard` for a part of the data, created by `WriteGuard::map`.
+pub struct MappedWriteGuard<'a, R: RawRwLock, T: ?Sized> {
+    raw: &'a R,
+    poison_flag: &'a poison::Flag,
+    poison: poison::Guard,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, R: RawRwLock + Sync, T: ?Sized + Sync> Sync for MappedWriteGuard<'a, R, T> {}
+
+impl<'a, R: RawRwLock, T: ?Sized> Deref for MappedWriteGuard<'a, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The lock is still write-locked, and the data was borrowed
+        // from it.
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<'a, R: RawRwLock, T: ?Sized> DerefMut for MappedWriteGuard<'a, R, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.data.as_mut() }
+    }
+}
+
+impl<'a, R: RawRwLock, T: ?Sized> Drop for MappedWriteGuard<'a, R, T> {
+    fn drop(&mut self) {
+        self.poison_flag.done(&self.poison);
+        // Safety: The guard was created by write-locking.
+        unsafe { self.raw.write_unlock() };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Lock, LockGuard, RawLock, RawTimedLock, RawTryLock, RwLock};
+    use crate::{
+        arc::Arc,
+        mutex::RawMutex,
+        rwlock,
+        spinlock::{ParkAfter, RawSpinLock},
+        ticket_lock::RawTicketLock,
+    };
+    use std::{thread, time::Duration};
+
+    static COUNTER: Lock<RawMutex, usize> = Lock::new(0);
+
+    fn count<R: RawLock + Sync>() {
+        let lock = Lock::<R, _>::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.into_inner(), 8000);
+    }
+
+    fn try_lock<R: RawTryLock>() {
+        let mut lock = Lock::<R, _>::new(0);
+        let guard = lock.try_lock().unwrap();
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        *lock.try_lock().unwrap() += 1;
+        assert_eq!(*lock.get_mut(), 1);
+    }
+
+    fn try_lock_for<R: RawTimedLock + Sync>() {
+        let lock = Lock::<R, _>::new(0);
+        let guard = lock.lock();
+        thread::scope(|s| {
+            s.spawn(|| assert!(lock.try_lock_for(Duration::from_millis(10)).is_none()));
+        });
+        drop(guard);
+        assert!(lock.try_lock_for(Duration::from_millis(10)).is_some());
+    }
+
+    #[test]
+    fn generic_locks() {
+        count::<RawMutex>();
+        count::<RawSpinLock>();
+        count::<RawSpinLock<ParkAfter>>();
+        count::<RawTicketLock>();
+        try_lock::<RawMutex>();
+        try_lock::<RawSpinLock>();
+        try_lock::<RawTicketLock>();
+        try_lock_for::<RawMutex>();
+    }
+
+    #[test]
+    fn in_static() {
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| *COUNTER.lock() += 1);
+            }
+        });
+        assert_eq!(*COUNTER.lock(), 4);
+    }
+
+    #[test]
+    fn shared_interface() {
+        // Poisoning, `map` and `lock_arc` work the same for every raw lock.
+        let lock = Arc::new(Lock::<RawTicketLock, _>::new_poisoning((0, 0)));
+        thread::scope(|s| {
+            let t = s.spawn(|| {
+                let _guard = lock.lock();
+                panic!("oops");
+            });
+            assert!(t.join().is_err());
+        });
+        assert!(lock.is_poisoned());
+        *LockGuard::map(lock.lock(), |data| &mut data.1) = 1;
+        let guard = Lock::lock_arc(&lock);
+        assert!(lock.is_locked());
+        drop(guard);
+        lock.clear_poison();
+        assert_eq!(*lock.lock_checked().unwrap(), (0, 1));
+    }
+
+    #[test]
+    fn rwlock() {
+        let lock = RwLock::<rwlock::RawRwLock, _>::new(0);
+        let (r1, r2) = (lock.read(), lock.read());
+        assert!(lock.try_write().is_none());
+        assert_eq!(*r1 + *r2, 0);
+        drop((r1, r2));
+        *lock.write() += 1;
+        let w = lock.try_write().unwrap();
+        assert!(lock.try_read().is_none());
+        drop(w);
+        assert_eq!(*lock.read(), 1);
+    }
+}
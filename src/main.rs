@@ -1,6 +1,21 @@
-use std::{thread, time::Instant};
+use std::{
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    thread,
+    time::{Duration, Instant},
+};
 
-use atomics::{mutex::Mutex, once_data, processor, spinlock};
+use atomics::{
+    clh_lock::ClhLock,
+    condvar::Condvar,
+    lock::Lock,
+    mcs_lock::McsLock,
+    mutex::{Mutex, MutexGuard},
+    once_data, processor, rwlock,
+    spinlock::{self, Backoff, Exponential, ParkAfter, RawSpinLock, Spin, SpinLock, YieldAfter},
+};
+
+mod rwlock1;
 
 fn main() {
     if false {
@@ -9,6 +24,11 @@ fn main() {
         processor::run();
     }
     bench_mutex();
+    bench_mutex_fairness();
+    bench_rwlock();
+    bench_notify_all();
+    bench_spinlock_backoff();
+    bench_queue_locks();
 }
 
 fn bench_mutex() {
@@ -27,3 +47,202 @@ fn bench_mutex() {
     let duration = start.elapsed();
     println!("locked {} times in {:?}", *m.lock(), duration);
 }
+
+/// Tail latency of acquiring a contended mutex, when unlocking normally vs
+/// handing the lock over with `unlock_fair`.
+fn bench_mutex_fairness() {
+    for fair in [false, true] {
+        let m = Mutex::new(0);
+        black_box(&m);
+        let mut latencies: Vec<Duration> = thread::scope(|s| {
+            let threads: Vec<_> = (0..10)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut latencies = Vec::with_capacity(100_000);
+                        for _ in 0..100_000 {
+                            let start = Instant::now();
+                            let mut guard = m.lock();
+                            latencies.push(start.elapsed());
+                            *guard += 1;
+                            if fair {
+                                MutexGuard::unlock_fair(guard);
+                            } else {
+                                drop(guard);
+                            }
+                        }
+                        latencies
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .collect()
+        });
+        latencies.sort();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "{} unlock: p50 {:?}, p99 {:?}, max {:?}",
+            if fair { "fair" } else { "normal" },
+            percentile(50),
+            percentile(99),
+            latencies.last().unwrap(),
+        );
+    }
+}
+
+/// Single-atomic `rwlock::RwLock` vs two-atomic `rwlock1::RwLock`.
+fn bench_rwlock() {
+    let lock = rwlock::RwLock::new(0);
+    let lock1 = rwlock1::RwLock::new(0);
+    black_box((&lock, &lock1));
+    for writes_every in [1_000_000, 10, 2] {
+        let duration = time_read_write(writes_every, || *lock.read(), || *lock.write() += 1);
+        println!("rwlock (1 write every {writes_every}): {duration:?}");
+        let duration = time_read_write(writes_every, || *lock1.read(), || *lock1.write() += 1);
+        println!("rwlock1 (1 write every {writes_every}): {duration:?}");
+    }
+}
+
+fn time_read_write(
+    writes_every: usize,
+    read: impl Fn() -> i32 + Sync,
+    write: impl Fn() + Sync,
+) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..10 {
+            s.spawn(|| {
+                for i in 0..1_000_000 {
+                    if i % writes_every == 0 {
+                        write();
+                    } else {
+                        black_box(read());
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+/// `notify_all` with dozens of waiters: our `Condvar`, which moves the waiters
+/// over to the mutex, vs `std::sync::Condvar`, which wakes all of them.
+fn bench_notify_all() {
+    const WAITERS: usize = 48;
+    const ROUNDS: usize = 1_000;
+
+    let m = Mutex::new(0);
+    let cv = Condvar::new();
+    let duration = time_notify_all(
+        WAITERS,
+        ROUNDS,
+        |round| drop(cv.wait_while(m.lock(), |r| *r < round)),
+        |round| {
+            *m.lock() = round;
+            cv.notify_all();
+        },
+    );
+    println!("condvar: {WAITERS} waiters, {ROUNDS} rounds in {duration:?}");
+
+    let m = std::sync::Mutex::new(0);
+    let cv = std::sync::Condvar::new();
+    let duration = time_notify_all(
+        WAITERS,
+        ROUNDS,
+        |round| drop(cv.wait_while(m.lock().unwrap(), |r| *r < round)),
+        |round| {
+            *m.lock().unwrap() = round;
+            cv.notify_all();
+        },
+    );
+    println!("std condvar: {WAITERS} waiters, {ROUNDS} rounds in {duration:?}");
+}
+
+/// Each round, `notify` wakes up all the `waiters` threads blocked in `wait`,
+/// and waits for them to get through.
+fn time_notify_all(
+    waiters: usize,
+    rounds: usize,
+    wait: impl Fn(usize) + Sync,
+    notify: impl Fn(usize),
+) -> Duration {
+    let done = AtomicUsize::new(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..waiters {
+            s.spawn(|| {
+                for round in 1..=rounds {
+                    wait(round);
+                    done.fetch_add(1, Relaxed);
+                }
+            });
+        }
+        for round in 1..=rounds {
+            notify(round);
+            while done.load(Relaxed) < waiters * round {
+                thread::yield_now();
+            }
+        }
+    });
+    start.elapsed()
+}
+
+/// The `SpinLock` backoff strategies, under increasing contention.
+fn bench_spinlock_backoff() {
+    for threads in [2, 8, 32] {
+        println!("spinlock, {threads} threads:");
+        println!("  spin: {:?}", time_spinlock::<Spin>(threads));
+        println!("  exponential: {:?}", time_spinlock::<Exponential>(threads));
+        println!(
+            "  yield after 100: {:?}",
+            time_spinlock::<YieldAfter>(threads)
+        );
+        println!(
+            "  park after 100: {:?}",
+            time_spinlock::<ParkAfter>(threads)
+        );
+    }
+}
+
+fn time_spinlock<B: Backoff>(threads: usize) -> Duration {
+    let lock = Lock::<RawSpinLock<B>, _>::new(0);
+    black_box(&lock);
+    time_increments(threads, || *lock.lock() += 1)
+}
+
+/// The MCS and CLH queue locks vs `SpinLock` and `Mutex`, as the number of
+/// threads grows.
+fn bench_queue_locks() {
+    for threads in [2, 8, 32] {
+        let spinlock = SpinLock::new(0);
+        let mutex = Mutex::new(0);
+        let mcs = McsLock::new(0);
+        let clh = ClhLock::new(0);
+        black_box((&spinlock, &mutex, &mcs, &clh));
+        println!("{threads} threads:");
+        let duration = time_increments(threads, || *spinlock.lock() += 1);
+        println!("  spinlock: {duration:?}");
+        let duration = time_increments(threads, || *mutex.lock() += 1);
+        println!("  mutex: {duration:?}");
+        let duration = time_increments(threads, || mcs.with_lock(|v| *v += 1));
+        println!("  mcs: {duration:?}");
+        let duration = time_increments(threads, || *clh.lock() += 1);
+        println!("  clh: {duration:?}");
+    }
+}
+
+/// Time `threads` threads calling `increment` 10,000 times each.
+fn time_increments(threads: usize, increment: impl Fn() + Sync) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..10_000 {
+                    increment();
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
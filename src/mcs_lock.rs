@@ -0,0 +1,163 @@
+use std::{
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering::*},
+    thread,
+};
+
+const MAX_SPINS: u32 = 100;
+
+/// A waiting thread's entry in the queue, on that thread's stack.
+#[repr(align(64))]
+struct Node {
+    /// The thread that queued up after this one.
+    next: AtomicPtr<Node>,
+    /// Cleared by the previous thread in the queue, when handing the lock over.
+    locked: AtomicBool,
+}
+
+/// An MCS queue lock.
+///
+/// Waiting threads queue up, and each one spins on a flag in its own queue
+/// node, instead of all of them spinning on the same atomic like `SpinLock`
+/// does. Unlocking only touches the next thread's node, so the lock scales to
+/// many cores, and it's handed over in FIFO order.
+///
+/// The nodes live on the stack of the waiting threads. A node must not go away
+/// while it's in the queue, so the lock is only held for the duration of a
+/// closure (see `with_lock`), instead of handing out a guard that could be
+/// leaked.
+pub struct McsLock<T> {
+    /// The last node in the queue, or null if the lock is free.
+    tail: AtomicPtr<Node>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for McsLock<T> where T: Send {}
+
+impl<T> McsLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Run `f` with the lock held.
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let node = Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            locked: AtomicBool::new(true),
+        };
+        self.lock(&node);
+        // Unlocks even if `f` panics. The guard can't be leaked, since it
+        // never leaves this function.
+        let guard = Guard {
+            lock: self,
+            node: &node,
+        };
+        f(unsafe { &mut *guard.lock.data.get() })
+    }
+
+    fn lock(&self, node: &Node) {
+        let node_ptr = node as *const Node as *mut Node;
+        // AcqRel: acquire the data released by the previous holder, if the
+        // lock was free. And release our node to the thread queueing up next.
+        let prev = self.tail.swap(node_ptr, AcqRel);
+        if prev.is_null() {
+            return;
+        }
+        // Safety: The previous node stays alive until it has handed the lock
+        // over to us, which needs this link.
+        unsafe { (*prev).next.store(node_ptr, Release) };
+        wait_while_locked(&node.locked);
+    }
+
+    fn unlock(&self, node: &Node) {
+        let mut next = node.next.load(Acquire);
+        if next.is_null() {
+            let node_ptr = node as *const Node as *mut Node;
+            // Nobody is queued up after us, so free the lock.
+            if self
+                .tail
+                .compare_exchange(node_ptr, ptr::null_mut(), Release, Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+            // Somebody just swapped themselves in as the tail, but hasn't
+            // linked themselves to our node yet.
+            loop {
+                next = node.next.load(Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                std::hint::spin_loop();
+            }
+        }
+        // Safety: The next thread is spinning on its node, which stays alive
+        // until this store. It's not touched afterwards.
+        unsafe { (*next).locked.store(false, Release) };
+    }
+}
+
+struct Guard<'a, T> {
+    lock: &'a McsLock<T>,
+    node: &'a Node,
+}
+
+impl<'a, T> Drop for Guard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock(self.node);
+    }
+}
+
+/// Spin until `locked` is cleared, yielding after a while.
+///
+/// The lock is handed over to one particular thread. If that thread isn't
+/// running (e.g. more threads than cores), spinning would only hold it up.
+///
+/// Also used by `ClhLock`.
+pub(crate) fn wait_while_locked(locked: &AtomicBool) {
+    let mut spins = 0;
+    while locked.load(Acquire) {
+        if spins < MAX_SPINS {
+            spins += 1;
+            std::hint::spin_loop();
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::McsLock;
+    use std::{panic, thread};
+
+    #[test]
+    fn add() {
+        let lock = McsLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        lock.with_lock(|v| *v += 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.with_lock(|v| *v), 8000);
+    }
+
+    #[test]
+    fn unlocks_on_panic() {
+        let lock = McsLock::new(0);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            lock.with_lock(|_| panic!("oops"));
+        }));
+        assert!(result.is_err());
+        lock.with_lock(|v| *v = 1);
+        assert_eq!(lock.with_lock(|v| *v), 1);
+    }
+}
@@ -1,37 +1,48 @@
 use std::{
-    cell::UnsafeCell,
-    ops::{Deref, DerefMut},
-    sync::atomic::{fence, AtomicU32, Ordering::*},
-    usize,
+    sync::atomic::{AtomicU32, Ordering::*},
+    time::{Duration, Instant},
 };
 
-use atomic_wait::{wait, wake_one};
+use atomic_wait::wake_one;
+
+use crate::{
+    futex,
+    lock::{self, ArcLockGuard, Lock, LockGuard, MappedLockGuard},
+};
 
 const UNLOCKED: u32 = 0;
 const LOCKED_UNCONTENDED: u32 = 1;
 const LOCKED_CONTENDED: u32 = 2;
+/// Locked, and handed over by the unlocking thread to a waiter it woke up.
+const HANDED_OFF: u32 = 3;
 
 const MAX_SPINS: usize = 100;
 
-pub struct Mutex<T> {
+/// Waiters that have been waiting longer than this ask for the lock to be
+/// handed over to them, instead of letting newcomers barge in.
+const FAIRNESS_THRESHOLD: Duration = Duration::from_millis(1);
+
+/// The futex state machine behind `Mutex`, without the data.
+pub struct RawMutex {
     /// 0: unlocked
     /// 1: locked, with no contention
     /// 2: locked, with other threads waiting
+    /// 3: handed off to a woken up waiter
     state: AtomicU32,
-    data: UnsafeCell<T>,
+    /// Number of waiters that have waited longer than `FAIRNESS_THRESHOLD`.
+    starving: AtomicU32,
 }
 
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
-
-impl<T> Mutex<T> {
-    pub const fn new(data: T) -> Self {
+impl RawMutex {
+    pub const fn new() -> Self {
         Self {
             state: AtomicU32::new(UNLOCKED),
-            data: UnsafeCell::new(data),
+            starving: AtomicU32::new(0),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
+    #[inline]
+    pub fn lock(&self) {
         // Succeeds if
         // - either this is the only thread trying to lock. We may be able to
         //   avoid the `wake` syscall in this case, if no other thread tries to
@@ -39,19 +50,26 @@ impl<T> Mutex<T> {
         // - this is the winner among all the threads trying to lock. The other
         //   threads will mark the lock as contended, and this thread will know
         //   that it has to `wake` another thread.
-        if self
-            .state
-            .compare_exchange(UNLOCKED, LOCKED_UNCONTENDED, Acquire, Relaxed)
-            .is_err()
-        {
-            self.lock_contended();
+        if !self.try_lock() {
+            self.lock_contended(None);
         }
-        MutexGuard { mutex: self }
     }
 
+    #[inline]
+    pub fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED_UNCONTENDED, Acquire, Relaxed)
+            .is_ok()
+    }
+
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.try_lock() || self.lock_contended(Some(deadline))
+    }
+
+    /// Returns `false` if the lock couldn't be acquired before the `deadline`.
     // The `cold` attribute suggests that the function is unlikely to be called.
     #[cold]
-    fn lock_contended(&self) {
+    fn lock_contended(&self, deadline: Option<Instant>) -> bool {
         let mut spins = 0;
         // Spin only if this is the only thread waiting on it. (Else all the
         // waiting threads will be spinning!)
@@ -59,71 +77,249 @@ impl<T> Mutex<T> {
             spins += 1;
             std::hint::spin_loop();
         }
+        self.try_lock() || self.wait_for_lock(deadline, false)
+    }
+
+    /// Lock, after being moved over from a `Condvar` to wait on `state`.
+    ///
+    /// The lock ends up `LOCKED_CONTENDED`, so that unlocking wakes up the
+    /// next thread that was moved over.
+    #[cold]
+    pub(crate) fn lock_requeued(&self) {
+        // This thread may have been woken up by a fair unlock, so it has to be
+        // able to take a handed off lock.
+        self.wait_for_lock(None, true);
+    }
+
+    fn wait_for_lock(&self, deadline: Option<Instant>, mut waited: bool) -> bool {
+        let start = Instant::now();
+        let mut starving = false;
+        // Only a thread that has been asleep can take a handed off lock: the
+        // unlocking thread relies on the one it woke up taking it.
+        let acquired = loop {
+            let s = self.state.load(Relaxed);
+            if s == HANDED_OFF {
+                if waited
+                    && self
+                        .state
+                        .compare_exchange(HANDED_OFF, LOCKED_CONTENDED, Acquire, Relaxed)
+                        .is_ok()
+                {
+                    break true;
+                }
+            } else if s != LOCKED_CONTENDED {
+                // Mark the lock as "contended" so that the locking thread
+                // knows it has to wake this. Or take it, if it's unlocked.
+                match self
+                    .state
+                    .compare_exchange(s, LOCKED_CONTENDED, Acquire, Relaxed)
+                {
+                    Ok(UNLOCKED) => break true,
+                    Ok(_) => {}
+                    Err(_) => continue,
+                }
+            }
+            // Wait while the locking thread releases the lock.
+            let s = if s == HANDED_OFF { s } else { LOCKED_CONTENDED };
+            if !futex::wait_until(&self.state, s, deadline) {
+                // Giving up leaves the state at LOCKED_CONTENDED, even if we
+                // were the only waiter. That's harmless: the locking thread
+                // makes one unnecessary `wake` call when unlocking, which
+                // resets the state to UNLOCKED.
+                //
+                // A thread that timed out wasn't woken up, so the lock can't
+                // have been handed off to it.
+                break false;
+            }
+            waited = true;
+            if !starving && start.elapsed() > FAIRNESS_THRESHOLD {
+                starving = true;
+                self.starving.fetch_add(1, Relaxed);
+            }
+        };
+        if starving {
+            self.starving.fetch_sub(1, Relaxed);
+        }
+        acquired
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) != UNLOCKED
+    }
+
+    #[inline]
+    pub(crate) fn unlock(&self) {
+        // Eventual fairness: let a waiter that has been waiting for too long
+        // have its turn.
+        if self.starving.load(Relaxed) > 0 {
+            return self.unlock_fair();
+        }
+        // We can avoid the syscall if there's no thread waiting.
+        if self.state.swap(UNLOCKED, Release) == LOCKED_CONTENDED {
+            wake_one(&self.state);
+        }
+    }
+
+    /// Unlock, handing the lock directly over to a waiting thread (if any).
+    ///
+    /// Plain `unlock` lets any thread take the lock, even one that just
+    /// arrived, before the woken up waiter gets to run.
+    pub(crate) fn unlock_fair(&self) {
         if self
             .state
-            .compare_exchange(UNLOCKED, LOCKED_UNCONTENDED, Acquire, Relaxed)
-            .is_ok()
+            .compare_exchange(LOCKED_CONTENDED, HANDED_OFF, Release, Relaxed)
+            .is_err()
         {
+            // Nobody waiting.
+            if self.state.swap(UNLOCKED, Release) == LOCKED_CONTENDED {
+                wake_one(&self.state);
+            }
+            return;
+        }
+        // The woken up thread takes it from here.
+        if futex::wake_one(&self.state) {
             return;
         }
-        // Wait while the locking thread releases the lock.
-        //
-        // Also mark the lock as "contended" so that the locking thread
-        // knows it has to wake this.
-        while self.state.swap(LOCKED_CONTENDED, Relaxed) != UNLOCKED {
-            wait(&self.state, LOCKED_CONTENDED);
+        self.cancel_handoff();
+    }
+
+    /// Nobody was asleep to take the handed off lock, so nobody might ever
+    /// take it: unlock it after all.
+    #[cold]
+    fn cancel_handoff(&self) {
+        // If the CAS fails, a waiter that was awake took the lock already.
+        if self
+            .state
+            .compare_exchange(HANDED_OFF, UNLOCKED, Release, Relaxed)
+            .is_ok()
+        {
+            // A thread that arrived after the failed `wake_one` may have gone
+            // to sleep on `HANDED_OFF`, which only a woken up waiter can take.
+            wake_one(&self.state);
         }
-        fence(Acquire);
+    }
+}
+
+impl Default for RawMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl lock::RawLock for RawMutex {
+    const INIT: Self = Self::new();
+
+    fn lock(&self) {
+        RawMutex::lock(self);
+    }
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
     }
 
-    #[cfg(test)]
     fn is_locked(&self) -> bool {
-        self.state.load(Relaxed) != UNLOCKED
+        RawMutex::is_locked(self)
     }
 
-    fn unlock(&self) {
-        // We can avoid the syscall if there's no thread waiting.
-        if self.state.swap(UNLOCKED, Release) == LOCKED_CONTENDED {
-            wake_one(&self.state);
-        }
+    fn futex(&self) -> Option<&AtomicU32> {
+        Some(&self.state)
+    }
+
+    fn lock_requeued(&self) {
+        RawMutex::lock_requeued(self);
     }
 }
 
-pub struct MutexGuard<'a, T> {
-    pub(super) mutex: &'a Mutex<T>,
+unsafe impl lock::RawTryLock for RawMutex {
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
+    }
 }
 
-impl<'a, T> Deref for MutexGuard<'a, T> {
-    type Target = T;
+unsafe impl lock::RawTimedLock for RawMutex {
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.mutex.data.get() }
+unsafe impl lock::RawFairLock for RawMutex {
+    unsafe fn unlock_fair(&self) {
+        RawMutex::unlock_fair(self);
     }
 }
 
-impl<'a, T> DerefMut for MutexGuard<'a, T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.mutex.data.get() }
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawMutex for RawMutex {
+    const INIT: Self = Self::new();
+
+    // Any thread can unlock.
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        RawMutex::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
+    }
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
+    }
+
+    fn is_locked(&self) -> bool {
+        RawMutex::is_locked(self)
     }
 }
 
-impl<'a, T> Drop for MutexGuard<'a, T> {
-    fn drop(&mut self) {
-        self.mutex.unlock();
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawMutexFair for RawMutex {
+    unsafe fn unlock_fair(&self) {
+        RawMutex::unlock_fair(self);
     }
 }
 
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawMutexTimed for RawMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        lock::RawTimedLock::try_lock_for(self, timeout)
+    }
+
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
+    }
+}
+
+/// A mutex that can be poisoned, if a thread panics while holding it.
+///
+/// Like for any `Lock`, poisoning is opt-in: only a mutex created with
+/// `Mutex::new_poisoning` gets poisoned, and only `lock_checked` reports it,
+/// like `std::sync::Mutex::lock` does.
+pub type Mutex<T> = Lock<RawMutex, T>;
+
+pub type MutexGuard<'a, T> = LockGuard<'a, RawMutex, T>;
+
+/// An owned `MutexGuard`, created by `Mutex::lock_arc`.
+pub type ArcMutexGuard<T> = ArcLockGuard<RawMutex, T>;
+
+/// A `MutexGuard` for a part of the data, created by `MutexGuard::map`.
+pub type MappedMutexGuard<'a, T> = MappedLockGuard<'a, RawMutex, T>;
+
 #[cfg(test)]
 mod test {
     use std::{
         sync::atomic::{AtomicU8, Ordering::*},
         thread,
-        time::Duration,
+        time::{Duration, Instant},
     };
 
     use rand::random;
 
-    use super::Mutex;
+    use super::{ArcMutexGuard, Mutex, MutexGuard, HANDED_OFF, LOCKED_CONTENDED};
+    use crate::{arc::Arc, futex};
 
     #[test]
     fn lock_unlock() {
@@ -150,6 +346,206 @@ mod test {
         assert_eq!(*m.lock(), 456);
     }
 
+    #[test]
+    fn try_lock() {
+        let m = Mutex::new(123);
+        let guard = m.try_lock().unwrap();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn try_lock_for() {
+        let m = Mutex::new(123);
+        thread::scope(|s| {
+            let mut guard = m.lock();
+            let t = s.spawn(|| {
+                let start = Instant::now();
+                assert!(m.try_lock_for(Duration::from_millis(100)).is_none());
+                assert!(start.elapsed() >= Duration::from_millis(100));
+
+                // Gets the lock once the main thread unlocks it.
+                *m.try_lock_for(Duration::from_secs(10)).unwrap()
+            });
+            thread::sleep(Duration::from_millis(200));
+            *guard = 456;
+            drop(guard);
+            assert_eq!(t.join().unwrap(), 456);
+        });
+    }
+
+    #[test]
+    fn timed_out_waiter_releases_state() {
+        let m = Mutex::new(123);
+        let guard = m.lock();
+        thread::scope(|s| {
+            s.spawn(|| assert!(m.try_lock_for(Duration::from_millis(10)).is_none()));
+        });
+        drop(guard);
+        assert!(!m.is_locked());
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn map() {
+        let m = Mutex::new((1, vec![2, 3]));
+        let mut first = MutexGuard::map(m.lock(), |data| &mut data.0);
+        *first = 4;
+        assert!(m.try_lock().is_none());
+        drop(first);
+
+        let guard = MutexGuard::try_map(m.lock(), |data| data.1.get_mut(5));
+        let guard = guard.err().unwrap();
+        let mut last = MutexGuard::try_map(guard, |data| data.1.last_mut())
+            .ok()
+            .unwrap();
+        *last = 5;
+        drop(last);
+        assert!(!m.is_locked());
+
+        let mut slice = MutexGuard::map(m.lock(), |data| &mut data.1[..]);
+        slice[0] = 6;
+        drop(slice);
+        assert_eq!(*m.lock(), (4, vec![6, 5]));
+    }
+
+    #[test]
+    fn get_mut_into_inner() {
+        let mut lock = Mutex::new(vec![1]);
+        lock.get_mut().push(2);
+        lock.lock().push(3);
+        assert_eq!(lock.into_inner(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn lock_arc() {
+        struct Holder {
+            guard: ArcMutexGuard<Vec<i32>>,
+        }
+
+        let m = Arc::new(Mutex::new(vec![1]));
+        let holder = Holder {
+            guard: Mutex::lock_arc(&m),
+        };
+        assert!(m.try_lock().is_none());
+        assert_eq!(Arc::strong_count(&m), 2);
+
+        // The guard can be moved to another thread, along with the lock.
+        let t = thread::spawn(move || {
+            let mut guard = holder.guard;
+            guard.push(2);
+        });
+        t.join().unwrap();
+
+        assert_eq!(Arc::strong_count(&m), 1);
+        assert_eq!(*m.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn unlock_fair() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            let guard = m.lock();
+            let waiter = s.spawn(|| *m.lock() += 1);
+            thread::sleep(Duration::from_millis(50));
+            MutexGuard::unlock_fair(guard);
+
+            // The lock has been handed to the waiter, not released.
+            waiter.join().unwrap();
+        });
+        assert!(!m.is_locked());
+        assert_eq!(*m.lock(), 1);
+
+        // Without any waiters, it's a normal unlock.
+        MutexGuard::unlock_fair(m.lock());
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn handoff_to_nobody() {
+        let m = Mutex::new(0);
+        let raw = m.raw();
+        let guard = m.lock();
+        // A waiter that timed out leaves the lock contended, with nobody
+        // asleep on it.
+        thread::scope(|s| {
+            s.spawn(|| assert!(m.try_lock_for(Duration::from_millis(10)).is_none()));
+        });
+
+        // Go through `unlock_fair` step by step, letting a newcomer go to
+        // sleep after the lock was handed off to nobody.
+        std::mem::forget(guard);
+        assert!(raw
+            .state
+            .compare_exchange(LOCKED_CONTENDED, HANDED_OFF, Release, Relaxed)
+            .is_ok());
+        assert!(!futex::wake_one(&raw.state));
+        thread::scope(|s| {
+            let newcomer = s.spawn(|| m.try_lock_for(Duration::from_secs(5)).is_some());
+            thread::sleep(Duration::from_millis(50));
+            raw.cancel_handoff();
+            assert!(newcomer.join().unwrap(), "the newcomer was never woken up");
+        });
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn fair_sync() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..1_000 {
+                        let mut guard = m.lock();
+                        *guard += 1;
+                        if i % 2 == 0 {
+                            MutexGuard::unlock_fair(guard);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 4_000);
+    }
+
+    #[test]
+    fn poison() {
+        let m = Mutex::new_poisoning(123);
+        thread::scope(|s| {
+            // Poisoned however the panicking thread locked it.
+            let t = s.spawn(|| {
+                let mut guard = m.lock();
+                *guard = 456;
+                panic!("oops");
+            });
+            assert!(t.join().is_err());
+        });
+        assert!(m.is_poisoned());
+
+        // `lock` doesn't care.
+        assert_eq!(*m.lock(), 456);
+
+        let guard = m.lock_checked().err().unwrap().into_inner();
+        assert_eq!(*guard, 456);
+        drop(guard);
+
+        m.clear_poison();
+        assert_eq!(*m.lock_checked().unwrap(), 456);
+
+        // A mutex from `new` never gets poisoned.
+        let m = Mutex::new(123);
+        thread::scope(|s| {
+            let t = s.spawn(|| {
+                let _guard = m.lock_checked().unwrap();
+                panic!("oops");
+            });
+            assert!(t.join().is_err());
+        });
+        assert!(!m.is_poisoned());
+        assert!(m.lock_checked().is_ok());
+    }
+
     #[test]
     fn sync() {
         for _ in 0..5 {
@@ -187,4 +583,29 @@ mod test {
             };
         }
     }
+
+    #[test]
+    #[cfg(feature = "lock_api")]
+    fn lock_api() {
+        use super::RawMutex;
+        use std::{thread, time::Duration};
+
+        let mutex = lock_api::Mutex::<RawMutex, _>::new((0, 0));
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        lock_api::MutexGuard::unlock_fair(mutex.lock());
+                        mutex.lock().0 += 1;
+                    }
+                });
+            }
+        });
+        let mut second = lock_api::MutexGuard::map(mutex.lock(), |data| &mut data.1);
+        *second += 1;
+        assert!(mutex.is_locked());
+        assert!(mutex.try_lock_for(Duration::from_millis(10)).is_none());
+        drop(second);
+        assert_eq!(*mutex.lock(), (4000, 1));
+    }
 }
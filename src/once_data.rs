@@ -1,12 +1,309 @@
+//! Values that are initialized once, and then only read.
+//!
+//! - `OnceBox` holds a pointer to the value. Initializing it may run the
+//!   initializer on several threads at once, keeping the first result.
+//! - `OnceCell` holds the value inline, and runs the initializer exactly once,
+//!   making the other threads wait.
+//! - `Lazy` is a `OnceCell` along with its initializer. `Lazy::new` is `const`,
+//!   so it can be used in a `static`.
+//! - `OnceData` is a `OnceBox` along with its initializer, in either mode.
+use std::cell::UnsafeCell;
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
 use std::ops::Deref;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
 
+use atomic_wait::{wait, wake_all};
+
+/// The initializer hasn't been run yet (or failed).
+const INCOMPLETE: u32 = 0;
+/// A thread is running the initializer.
+const RUNNING: u32 = 1;
+const COMPLETE: u32 = 2;
+
+/// Runs an initializer exactly once, making the other threads wait for it.
+struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(INCOMPLETE),
+        }
+    }
+
+    fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Run `f`, unless it has completed already (on any thread). If another
+    /// thread is running it, wait for that to finish.
+    ///
+    /// If `f` fails or panics, the next thread to call this runs it again.
+    #[cold]
+    fn call<E>(&self, f: impl FnOnce() -> Result<(), E>) -> Result<(), E> {
+        loop {
+            match self.state.compare_exchange(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    // Let the next thread have a go, if `f` fails.
+                    let reset = Reset(&self.state);
+                    f()?;
+                    std::mem::forget(reset);
+                    self.state.store(COMPLETE, Ordering::Release);
+                    wake_all(&self.state);
+                    return Ok(());
+                }
+                Err(COMPLETE) => return Ok(()),
+                Err(_) => wait(&self.state, RUNNING),
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self.state.get_mut() = INCOMPLETE;
+    }
+}
+
+/// Resets the state to `INCOMPLETE` when dropped, waking up the waiters.
+struct Reset<'a>(&'a AtomicU32);
+
+impl Drop for Reset<'_> {
+    fn drop(&mut self) {
+        self.0.store(INCOMPLETE, Ordering::Release);
+        wake_all(self.0);
+    }
+}
+
+/// A value that's set once, through a pointer to it.
+///
+/// Several threads may run the initializer at the same time. The first value
+/// to be set wins, and the others are dropped.
+pub struct OnceBox<T> {
+    data: AtomicPtr<T>,
+    _marker: PhantomData<Box<T>>,
+}
+
+// The value may be created on one thread and dropped on another.
+unsafe impl<T: Send> Send for OnceBox<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceBox<T> {}
+
+impl<T> OnceBox<T> {
+    pub const fn new() -> Self {
+        Self {
+            data: AtomicPtr::new(ptr::null_mut()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        let p = self.data.load(Ordering::Acquire);
+        // Safety: Once set, the data is only freed through `&mut self`.
+        unsafe { p.as_ref() }
+    }
+
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.get_or_try_init(|| Ok::<T, Infallible>(f())) {
+            Ok(data) => data,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like `get_or_init`, but leaves the box empty if `f` fails.
+    pub fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if let Some(data) = self.get() {
+            return Ok(data);
+        }
+        Ok(match self.init(Box::new(f()?)) {
+            Ok(data) => data,
+            // Another thread won the race.
+            Err((data, _)) => data,
+        })
+    }
+
+    /// Gives `value` back if the box has been set already.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        self.init(Box::new(value))
+            .map(|_| ())
+            .map_err(|(_, value)| *value)
+    }
+
+    /// Returns the data that was set by another thread in the meantime (and
+    /// `data` itself) if it loses the race.
+    fn init(&self, data: Box<T>) -> Result<&T, (&T, Box<T>)> {
+        let data = Box::into_raw(data);
+        match self.data.compare_exchange(
+            ptr::null_mut(),
+            data,
+            Ordering::Release,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => Ok(unsafe { &*data }),
+            Err(d) => Err(unsafe { (&*d, Box::from_raw(data)) }),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe { self.data.get_mut().as_mut() }
+    }
+
+    /// Take the data out, leaving the box empty.
+    pub fn take(&mut self) -> Option<T> {
+        let p = std::mem::replace(self.data.get_mut(), ptr::null_mut());
+        (!p.is_null()).then(|| *unsafe { Box::from_raw(p) })
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+}
+
+impl<T> Default for OnceBox<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceBox<T> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+/// A value that's set once, in place. The initializer runs exactly once, and
+/// the other threads wait for it.
+pub struct OnceCell<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        // Safety: The value is written before completing, and never changed
+        // after that, except through `&mut self`.
+        self.once
+            .is_completed()
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.get_or_try_init(|| Ok::<T, Infallible>(f())) {
+            Ok(value) => value,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like `get_or_init`, but leaves the cell empty if `f` fails, so that a
+    /// later call can try again.
+    pub fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        self.once.call(|| {
+            let value = f()?;
+            // Safety: Only the thread running the initializer gets here.
+            unsafe { (*self.value.get()).write(value) };
+            Ok(())
+        })?;
+        Ok(unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Gives `value` back if the cell has been set already.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        value.map_or(Ok(()), Err)
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        (*self.once.state.get_mut() == COMPLETE)
+            .then(|| unsafe { self.value.get_mut().assume_init_mut() })
+    }
+
+    /// Take the value out, leaving the cell empty.
+    pub fn take(&mut self) -> Option<T> {
+        if *self.once.state.get_mut() != COMPLETE {
+            return None;
+        }
+        self.once.reset();
+        Some(unsafe { self.value.get_mut().assume_init_read() })
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+/// A value that's initialized on first use, by running `F` exactly once.
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    /// Taken by the thread that runs it.
+    init: UnsafeCell<Option<F>>,
+}
+
+// Only the thread running the initializer touches `init`.
+unsafe impl<T, F: Send> Sync for Lazy<T, F> where OnceCell<T>: Sync {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            cell: OnceCell::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.cell.get_or_init(|| {
+            // Safety: Only the thread running the initializer gets here.
+            let init = unsafe { (*self.init.get()).take() };
+            init.expect("Lazy initializer panicked before")()
+        })
+    }
+}
+
+/// A value created by `f` on first use, through a `OnceBox`.
 pub struct OnceData<F, T>
 where
     F: Fn() -> T,
 {
     f: F,
-    data: AtomicPtr<T>,
+    data: OnceBox<T>,
+    /// Only used with `new_blocking`.
+    once: Once,
+    blocking: bool,
 }
 
 impl<F, T> OnceData<F, T>
@@ -19,33 +316,85 @@ where
     pub fn new(f: F) -> Self {
         Self {
             f,
-            data: AtomicPtr::new(std::ptr::null_mut()),
+            data: OnceBox::new(),
+            once: Once::new(),
+            blocking: false,
         }
     }
 
-    fn get(&self) -> &'static T {
-        let mut p = self.data.load(Ordering::Acquire);
-        if p.is_null() {
-            let data = Box::into_raw(Box::new((self.f)()));
-            p = match self
-                .data
-                .compare_exchange_weak(p, data, Ordering::Release, Ordering::Acquire)
-            {
-                Ok(_) => {
-                    self.data.store(data, Ordering::Release);
-                    data
-                }
-                Err(d) => {
-                    drop(unsafe { Box::from_raw(data) });
-                    d
-                }
-            }
+    /// Like `new`, but `f` is run exactly once: the other threads calling `get`
+    /// in the meantime wait for it to finish.
+    ///
+    /// If `f` panics, the next thread to call `get` runs it again.
+    pub fn new_blocking(f: F) -> Self {
+        Self {
+            blocking: true,
+            ..Self::new(f)
         }
-        unsafe { &*p }
+    }
+
+    /// The data, running `f` first if it hasn't been run yet.
+    pub fn get(&self) -> &T {
+        match self.get_or_try_init(|| Ok::<T, Infallible>((self.f)())) {
+            Ok(data) => data,
+            Err(e) => match e {},
+        }
+    }
+
+    /// The data, if it has been set. Never runs `f`, or waits for it.
+    pub fn try_get(&self) -> Option<&T> {
+        self.data.get()
+    }
+
+    /// The data, running `init` instead of `f` if it hasn't been set yet. If
+    /// `init` fails, the data is left unset, so a later call can try again.
+    pub fn get_or_try_init<E>(&self, init: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if let Some(data) = self.data.get() {
+            return Ok(data);
+        }
+        if !self.blocking {
+            return self.data.get_or_try_init(init);
+        }
+        self.once.call(|| {
+            // Only set through `self.once`, so it can't have been set yet.
+            let _ = self.data.set(init()?);
+            Ok(())
+        })?;
+        Ok(self.data.get().unwrap())
+    }
+
+    /// Set the data, instead of running `f`. Gives `value` back if the data
+    /// has been set already.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if !self.blocking {
+            return self.data.set(value);
+        }
+        let mut value = Some(value);
+        let _ = self.once.call(|| {
+            let _ = self.data.set(value.take().unwrap());
+            Ok::<(), Infallible>(())
+        });
+        value.map_or(Ok(()), Err)
+    }
+
+    /// Take the data out, leaving the `OnceData` as if `f` had never been
+    /// run.
+    pub fn take(&mut self) -> Option<T> {
+        self.once.reset();
+        self.data.take()
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.data.into_inner()
+    }
+
+    /// The data, if it has been set. Doesn't run `f`.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.data.get_mut()
     }
 }
 
-impl<F, T: 'static> Deref for OnceData<F, T>
+impl<F, T> Deref for OnceData<F, T>
 where
     F: Fn() -> T,
 {
@@ -85,3 +434,197 @@ pub mod check {
         println!("final: {}", get_data().value);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Lazy, OnceBox, OnceCell, OnceData};
+    use std::{
+        panic,
+        sync::atomic::{AtomicUsize, Ordering::Relaxed},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn racy() {
+        let once = OnceData::new(|| 1);
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| assert_eq!(*once, 1));
+            }
+        });
+    }
+
+    #[test]
+    fn blocking_runs_once() {
+        let calls = AtomicUsize::new(0);
+        let once = OnceData::new_blocking(|| {
+            thread::sleep(Duration::from_millis(10));
+            calls.fetch_add(1, Relaxed)
+        });
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| assert_eq!(*once, 0));
+            }
+        });
+        assert_eq!(calls.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn blocking_retries_after_panic() {
+        let calls = AtomicUsize::new(0);
+        let once = OnceData::new_blocking(|| {
+            if calls.fetch_add(1, Relaxed) == 0 {
+                panic!("first call fails");
+            }
+            1
+        });
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| *once));
+        assert!(result.is_err());
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| assert_eq!(*once, 1));
+            }
+        });
+        assert_eq!(calls.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn drop_frees_data() {
+        struct Counted<'a>(&'a AtomicUsize);
+
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Relaxed);
+            }
+        }
+
+        let dropped = AtomicUsize::new(0);
+        let f = || Counted(&dropped);
+        for (i, once) in [OnceData::new(f), OnceData::new_blocking(f)]
+            .into_iter()
+            .enumerate()
+        {
+            let _ = once.get();
+            assert_eq!(dropped.load(Relaxed), i);
+            drop(once);
+            assert_eq!(dropped.load(Relaxed), i + 1);
+        }
+
+        // The racy mode drops the values that lost the race.
+        dropped.store(0, Relaxed);
+        let once = OnceData::new(|| Counted(&dropped));
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| {
+                    let _ = once.get();
+                });
+            }
+        });
+        drop(once);
+        assert!(dropped.load(Relaxed) >= 1);
+    }
+
+    #[test]
+    fn set_take() {
+        let f = || 1;
+        for mut once in [OnceData::new(f), OnceData::new_blocking(f)] {
+            assert_eq!(once.get_mut(), None);
+            assert_eq!(once.set(2), Ok(()));
+            assert_eq!(once.set(3), Err(3));
+            assert_eq!(*once, 2);
+            *once.get_mut().unwrap() = 4;
+            assert_eq!(once.take(), Some(4));
+            assert_eq!(once.take(), None);
+            // Runs `f` again.
+            assert_eq!(*once.get(), 1);
+            assert_eq!(once.into_inner(), Some(1));
+        }
+    }
+
+    static LAZY: Lazy<Vec<u32>> = Lazy::new(|| vec![1, 2, 3]);
+
+    #[test]
+    fn lazy() {
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| assert_eq!(LAZY.len(), 3));
+            }
+        });
+
+        let calls = AtomicUsize::new(0);
+        let lazy = Lazy::new(|| calls.fetch_add(1, Relaxed));
+        assert_eq!(*lazy, 0);
+        assert_eq!(*lazy, 0);
+        assert_eq!(calls.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn once_cell() {
+        let calls = AtomicUsize::new(0);
+        let cell = OnceCell::new();
+        assert_eq!(cell.get(), None);
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| {
+                    let value = cell.get_or_init(|| {
+                        thread::sleep(Duration::from_millis(10));
+                        calls.fetch_add(1, Relaxed)
+                    });
+                    assert_eq!(*value, 0);
+                });
+            }
+        });
+        assert_eq!(calls.load(Relaxed), 1);
+        assert_eq!(cell.set(1), Err(1));
+        assert_eq!(cell.into_inner(), Some(0));
+    }
+
+    #[test]
+    fn once_cell_try_init() {
+        let mut cell = OnceCell::new();
+        assert_eq!(cell.get_or_try_init(|| Err("failed")), Err("failed"));
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(1)), Ok(&1));
+        assert_eq!(cell.get_or_try_init(|| Err(())), Ok(&1));
+        assert_eq!(cell.take(), Some(1));
+        assert_eq!(cell.set(2), Ok(()));
+        assert_eq!(cell.get_mut(), Some(&mut 2));
+    }
+
+    #[test]
+    fn once_box() {
+        static BOX: OnceBox<String> = OnceBox::new();
+        thread::scope(|s| {
+            for i in 0..10 {
+                s.spawn(move || {
+                    let value = BOX.get_or_init(|| i.to_string());
+                    assert_eq!(BOX.get(), Some(value));
+                });
+            }
+        });
+        let value = BOX.get().unwrap();
+        assert_eq!(BOX.set(String::new()), Err(String::new()));
+        assert_eq!(BOX.get(), Some(value));
+
+        let mut once_box = OnceBox::new();
+        assert_eq!(once_box.get_or_try_init(|| Err(())), Err(()));
+        assert_eq!(once_box.set(1), Ok(()));
+        assert_eq!(once_box.take(), Some(1));
+        assert_eq!(once_box.into_inner(), None);
+    }
+
+    #[test]
+    fn try_init() {
+        let f = || 1;
+        for once in [OnceData::new(f), OnceData::new_blocking(f)] {
+            assert_eq!(once.try_get(), None);
+            assert_eq!(once.get_or_try_init(|| Err("failed")), Err("failed"));
+            assert_eq!(once.try_get(), None);
+            assert_eq!(once.get_or_try_init(|| Ok::<_, ()>(2)), Ok(&2));
+            assert_eq!(once.get_or_try_init(|| Err(())), Ok(&2));
+            assert_eq!(*once, 2);
+            assert_eq!(once.try_get(), Some(&2));
+        }
+    }
+}
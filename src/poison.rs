@@ -0,0 +1,112 @@
+//! Lock poisoning, like `std::sync`'s.
+//!
+//! A lock is poisoned when a thread panics while holding it exclusively, since
+//! the protected data might have been left half-updated.
+use std::{
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering::*},
+    thread,
+};
+
+pub(crate) struct Flag {
+    failed: AtomicBool,
+    /// Whether the lock gets poisoned at all. Fixed when the lock is created.
+    enabled: bool,
+}
+
+impl Flag {
+    pub const fn new(enabled: bool) -> Self {
+        Self {
+            failed: AtomicBool::new(false),
+            enabled,
+        }
+    }
+
+    /// To be called right after acquiring the lock exclusively. The returned
+    /// `Guard` has to be passed to `done` before releasing it.
+    ///
+    /// A lock acquired while already unwinding (e.g. in some `Drop` impl)
+    /// isn't poisoned. Neither is one that doesn't get poisoned at all, and
+    /// then `done` doesn't even check whether the thread is panicking.
+    #[inline]
+    pub fn guard(&self) -> Guard {
+        Guard {
+            poisons: self.enabled && !thread::panicking(),
+        }
+    }
+
+    /// Poisons the lock if the thread started panicking since it was locked.
+    #[inline]
+    pub fn done(&self, guard: &Guard) {
+        if guard.poisons && thread::panicking() {
+            self.failed.store(true, Relaxed);
+        }
+    }
+
+    // Relaxed is enough: the lock itself synchronizes access to the data.
+    #[inline]
+    pub fn get(&self) -> bool {
+        self.failed.load(Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.failed.store(false, Relaxed);
+    }
+}
+
+/// Whether a guard poisons its lock, if the thread panics while holding it.
+#[derive(Clone, Copy)]
+pub struct Guard {
+    poisons: bool,
+}
+
+/// Returned when acquiring a poisoned lock. It still carries the guard, so
+/// the data can be inspected and repaired.
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+impl<G> PoisonError<G> {
+    pub(crate) fn new(guard: G) -> Self {
+        Self { guard }
+    }
+
+    /// Ignore the poisoning and take the guard anyway.
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("poisoned lock: another thread panicked while holding it")
+    }
+}
+
+impl<G> Error for PoisonError<G> {}
+
+/// Wraps the guard in a `PoisonError` if the lock is poisoned.
+pub(crate) fn check<G>(flag: &Flag, guard: G) -> LockResult<G> {
+    if flag.get() {
+        Err(PoisonError::new(guard))
+    } else {
+        Ok(guard)
+    }
+}
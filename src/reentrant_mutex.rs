@@ -0,0 +1,168 @@
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering::*},
+};
+
+use crate::mutex::RawMutex;
+
+/// A mutex that the thread holding it can lock again, without deadlocking.
+///
+/// Since the same thread can hold several guards at once, they only give out
+/// shared references. Use a `Cell` or `RefCell` for mutation.
+pub struct ReentrantMutex<T> {
+    raw: RawMutex,
+    /// Id of the thread holding the lock, or 0 if it's unlocked.
+    owner: AtomicUsize,
+    /// Number of guards held by the owner. Only accessed by the owner.
+    count: UnsafeCell<usize>,
+    data: T,
+}
+
+unsafe impl<T> Sync for ReentrantMutex<T> where T: Send {}
+
+/// A non-zero id, unique for every thread.
+///
+/// Unlike the address of a thread local, the id isn't reused by a later thread,
+/// which would then be able to "re-enter" a lock leaked by an exited thread.
+fn current_thread_id() -> usize {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+    thread_local! {
+        static ID: usize = NEXT_ID.fetch_add(1, Relaxed);
+    }
+    ID.with(|id| *id)
+}
+
+impl<T> ReentrantMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            raw: RawMutex::new(),
+            owner: AtomicUsize::new(0),
+            count: UnsafeCell::new(0),
+            data,
+        }
+    }
+
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        let this_thread = current_thread_id();
+        if !self.relock(this_thread) {
+            self.raw.lock();
+            self.acquired(this_thread);
+        }
+        ReentrantMutexGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Acquire the lock only if it's free, or already held by this thread.
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        let this_thread = current_thread_id();
+        if !self.relock(this_thread) {
+            if !self.raw.try_lock() {
+                return None;
+            }
+            self.acquired(this_thread);
+        }
+        Some(ReentrantMutexGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Bump the count if this thread already holds the lock.
+    fn relock(&self, this_thread: usize) -> bool {
+        // Relaxed is enough: only this thread could have stored its own id. Any
+        // other value means that it doesn't hold the lock.
+        if self.owner.load(Relaxed) != this_thread {
+            return false;
+        }
+        // Safety: we're the owner, which is the only thread accessing `count`.
+        let count = unsafe { &mut *self.count.get() };
+        *count = count.checked_add(1).expect("lock count overflow");
+        true
+    }
+
+    fn acquired(&self, this_thread: usize) {
+        self.owner.store(this_thread, Relaxed);
+        // Safety: we just locked `raw`.
+        unsafe { *self.count.get() = 1 };
+    }
+
+    fn unlock(&self) {
+        // Safety: only the owner holds guards.
+        let count = unsafe { &mut *self.count.get() };
+        *count -= 1;
+        if *count == 0 {
+            self.owner.store(0, Relaxed);
+            self.raw.unlock();
+        }
+    }
+}
+
+/// The guard is neither `Send` nor `Sync`, so it can't be dropped (i.e.
+/// unlocked) on any thread but the owner.
+pub struct ReentrantMutexGuard<'a, T> {
+    lock: &'a ReentrantMutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> Deref for ReentrantMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lock.data
+    }
+}
+
+impl<'a, T> Drop for ReentrantMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::{Cell, RefCell},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::ReentrantMutex;
+
+    #[test]
+    fn reentrant() {
+        let m = ReentrantMutex::new(RefCell::new(vec![1]));
+        let outer = m.lock();
+        let inner = m.lock();
+        inner.borrow_mut().push(2);
+        assert!(m.try_lock().is_some());
+        drop(inner);
+        assert_eq!(*outer.borrow(), vec![1, 2]);
+    }
+
+    #[test]
+    fn other_threads_wait() {
+        let m = ReentrantMutex::new(Cell::new(0));
+        thread::scope(|s| {
+            let outer = m.lock();
+            let inner = m.lock();
+            let t = s.spawn(|| {
+                assert!(m.try_lock().is_none());
+                let start = Instant::now();
+                let guard = m.lock();
+                guard.set(guard.get() + 1);
+                start.elapsed()
+            });
+            thread::sleep(Duration::from_millis(50));
+            drop(inner);
+            thread::sleep(Duration::from_millis(50));
+            outer.set(1);
+            drop(outer);
+            assert!(t.join().unwrap() >= Duration::from_millis(100));
+        });
+        assert_eq!(m.lock().get(), 2);
+    }
+}
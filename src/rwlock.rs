@@ -1,157 +1,546 @@
 use std::{
-    cell::UnsafeCell,
-    ops::{Deref, DerefMut},
-    sync::atomic::{fence, AtomicU32, Ordering::*},
+    sync::atomic::{AtomicU32, Ordering::*},
+    time::Instant,
 };
 
 use atomic_wait::{wait, wake_all, wake_one};
 
+use crate::{futex, lock};
+
 const UNLOCKED: u32 = 0;
-const READ_LOCK: u32 = 1;
-const WRITE_LOCK: u32 = 2;
+/// Set while a writer holds the lock.
+const WRITE_LOCKED: u32 = 1;
+/// Set while there might be writers waiting for the lock.
+const WRITERS_WAITING: u32 = 1 << 1;
+/// Set while an upgradable reader holds the lock. It's also counted as one of
+/// the readers.
+const UPGRADABLE: u32 = 1 << 2;
+/// Every reader holding the lock adds this to the state.
+const READER: u32 = 1 << 3;
+const MAX_READERS: u32 = u32::MAX / READER;
+
+#[inline]
+fn readers(state: u32) -> u32 {
+    state / READER
+}
 
-pub struct RwLock<T> {
-    readers: AtomicU32,
+/// The state machine behind `RwLock`, without the data.
+pub struct RawRwLock {
+    /// The number of readers (in units of `READER`), along with the
+    /// `WRITE_LOCKED`, `WRITERS_WAITING` and `UPGRADABLE` bits.
+    ///
+    /// Readers wait on this directly. Writers wait on `writer_wake_counter`
+    /// instead, so that readers coming and going don't wake them up.
     state: AtomicU32,
-    data: UnsafeCell<T>,
+    /// Incremented to wake up a writer.
+    writer_wake_counter: AtomicU32,
+    prefer_writers: bool,
 }
 
-// In addition to the `Send` constraint like in `Mutex`, we additionally need
-// the `Sync` constraint since multiple readers can access the data.
-unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
-
-impl<T> RwLock<T> {
-    pub fn new(data: T) -> Self {
+impl RawRwLock {
+    pub const fn new(prefer_writers: bool) -> Self {
         Self {
-            readers: AtomicU32::new(0),
             state: AtomicU32::new(UNLOCKED),
-            data: UnsafeCell::new(data),
+            writer_wake_counter: AtomicU32::new(0),
+            prefer_writers,
         }
     }
 
-    pub fn read(&self) -> RLockGuard<T> {
-        let mut readers = self.readers.load(Relaxed);
-        loop {
+    #[inline]
+    fn is_read_lockable(&self, state: u32) -> bool {
+        state & WRITE_LOCKED == 0 && !(self.prefer_writers && state & WRITERS_WAITING != 0)
+    }
+
+    #[inline]
+    pub fn read(&self) {
+        let s = self.state.load(Relaxed);
+        if !(self.is_read_lockable(s)
+            && readers(s) < MAX_READERS
+            && self
+                .state
+                .compare_exchange_weak(s, s + READER, Acquire, Relaxed)
+                .is_ok())
+        {
+            self.read_contended(None);
+        }
+    }
+
+    pub fn try_read(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while self.is_read_lockable(s) && readers(s) < MAX_READERS {
             match self
-                .readers
-                .compare_exchange(readers, readers + 1, Relaxed, Relaxed)
+                .state
+                .compare_exchange_weak(s, s + READER, Acquire, Relaxed)
             {
-                Ok(_) => break,
-                Err(val) => {
-                    readers = val;
-                    while readers == u32::MAX {
-                        readers = self.readers.load(Relaxed);
-                        std::hint::spin_loop();
-                    }
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    pub fn try_read_until(&self, deadline: Instant) -> bool {
+        self.try_read() || self.read_contended(Some(deadline))
+    }
+
+    /// Returns `false` if the lock couldn't be acquired before the `deadline`.
+    #[cold]
+    fn read_contended(&self, deadline: Option<Instant>) -> bool {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if self.is_read_lockable(s) {
+                assert!(readers(s) < MAX_READERS, "too many readers");
+                match self
+                    .state
+                    .compare_exchange_weak(s, s + READER, Acquire, Relaxed)
+                {
+                    Ok(_) => return true,
+                    Err(e) => s = e,
                 }
-            };
+                continue;
+            }
+            // Woken up by `write_unlock`. Readers don't touch the state
+            // before they get the lock, so there's nothing to undo if we time
+            // out.
+            if !futex::wait_until(&self.state, s, deadline) {
+                return false;
+            }
+            s = self.state.load(Relaxed);
         }
+    }
+
+    pub fn upgradable_read(&self) {
+        let mut s = self.state.load(Relaxed);
         loop {
+            if self.is_read_lockable(s) && s & UPGRADABLE == 0 {
+                assert!(readers(s) < MAX_READERS, "too many readers");
+                match self
+                    .state
+                    .compare_exchange_weak(s, s + READER + UPGRADABLE, Acquire, Relaxed)
+                {
+                    Ok(_) => return,
+                    Err(e) => s = e,
+                }
+                continue;
+            }
+            // Woken up by `write_unlock` or `upgradable_unlock`.
+            wait(&self.state, s);
+            s = self.state.load(Relaxed);
+        }
+    }
+
+    pub fn try_upgradable_read(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while self.is_read_lockable(s) && s & UPGRADABLE == 0 && readers(s) < MAX_READERS {
             match self
                 .state
-                .compare_exchange(UNLOCKED, READ_LOCK, Acquire, Relaxed)
+                .compare_exchange_weak(s, s + READER + UPGRADABLE, Acquire, Relaxed)
             {
-                Ok(_) => return RLockGuard { lock: &self },
-                Err(state) => {
-                    if state == READ_LOCK {
-                        fence(Acquire);
-                        return RLockGuard { lock: &self };
-                    } else {
-                        wait(&self.state, WRITE_LOCK);
-                    }
-                }
+                Ok(_) => return true,
+                Err(e) => s = e,
             }
         }
+        false
     }
 
-    pub fn write(&self) -> WLockGuard<T> {
-        loop {
+    #[inline]
+    pub fn write(&self) {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, WRITE_LOCKED, Acquire, Relaxed)
+            .is_err()
+        {
+            self.write_contended(None);
+        }
+    }
+
+    pub fn try_write(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        // Unlocked, even if there are other writers waiting.
+        while s & !WRITERS_WAITING == 0 {
             match self
                 .state
-                .compare_exchange(UNLOCKED, WRITE_LOCK, Acquire, Relaxed)
+                .compare_exchange_weak(s, s | WRITE_LOCKED, Acquire, Relaxed)
             {
-                Ok(_) => return WLockGuard { lock: &self },
-                Err(state) => wait(&self.state, state),
+                Ok(_) => return true,
+                Err(e) => s = e,
             }
         }
+        false
+    }
+
+    pub fn try_write_until(&self, deadline: Instant) -> bool {
+        self.state
+            .compare_exchange(UNLOCKED, WRITE_LOCKED, Acquire, Relaxed)
+            .is_ok()
+            || self.write_contended(Some(deadline))
+    }
+
+    /// Returns `false` if the lock couldn't be acquired before the `deadline`.
+    #[cold]
+    fn write_contended(&self, deadline: Option<Instant>) -> bool {
+        let mut s = self.state.load(Relaxed);
+        // Unlocking clears the `WRITERS_WAITING` bit and wakes only one
+        // writer. So once we've waited, we have to assume that there are
+        // other writers waiting, and set the bit again when locking.
+        let mut other_writers_waiting = 0;
+        loop {
+            // Unlocked, even if there are other writers waiting.
+            if s & !WRITERS_WAITING == 0 {
+                match self.state.compare_exchange_weak(
+                    s,
+                    s | WRITE_LOCKED | other_writers_waiting,
+                    Acquire,
+                    Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(e) => s = e,
+                }
+                continue;
+            }
+            if s & WRITERS_WAITING == 0 {
+                if let Err(e) =
+                    self.state
+                        .compare_exchange(s, s | WRITERS_WAITING, Relaxed, Relaxed)
+                {
+                    s = e;
+                    continue;
+                }
+            }
+            other_writers_waiting = WRITERS_WAITING;
+
+            // Load the counter before checking the state again, so that an
+            // unlock in between can't be missed.
+            let w = self.writer_wake_counter.load(Acquire);
+            s = self.state.load(Relaxed);
+            if s & WRITERS_WAITING != 0 && s != WRITERS_WAITING {
+                if !futex::wait_until(&self.writer_wake_counter, w, deadline) {
+                    self.write_timed_out();
+                    return false;
+                }
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    /// Clean up after a writer that stopped waiting.
+    ///
+    /// The `WRITERS_WAITING` bit it set might be the only thing blocking new
+    /// readers, so it has to be cleared. Since there's no telling if other
+    /// writers (or an upgrader) are relying on the bit too, they're all woken
+    /// up to set it again.
+    #[cold]
+    fn write_timed_out(&self) {
+        self.state.fetch_and(!WRITERS_WAITING, Relaxed);
+        self.writer_wake_counter.fetch_add(1, Release);
+        wake_all(&self.writer_wake_counter);
+        wake_all(&self.state);
     }
 
     #[inline]
-    fn read_unlock(&self) {
-        if self.readers.fetch_sub(1, Release) > 1 {
-            return;
+    pub(crate) fn read_unlock(&self) {
+        let s = self.state.fetch_sub(READER, Release) - READER;
+        if s == WRITERS_WAITING {
+            // Wake a waiting writer, if this was the last reader.
+            self.wake_writer();
+        } else if s == READER | UPGRADABLE | WRITERS_WAITING {
+            // Only the upgradable reader is left, which might be waiting to
+            // upgrade. Other waiting writers can't make progress, but there's
+            // no way to wake up the upgrader specifically.
+            self.writer_wake_counter.fetch_add(1, Release);
+            wake_all(&self.writer_wake_counter);
         }
-        // set to a dummy value temporarily, so that no new reader is added
-        // while we unlock.
-        match self.readers.compare_exchange(0, u32::MAX, Relaxed, Relaxed) {
-            Ok(_) => {
-                self.state.store(UNLOCKED, Release);
-                self.readers.store(0, Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn upgradable_unlock(&self) {
+        let s = self.state.fetch_sub(READER + UPGRADABLE, Release) - READER - UPGRADABLE;
+        if s == WRITERS_WAITING {
+            self.wake_writer();
+        }
+        // Wake the upgradable readers waiting for this one.
+        wake_all(&self.state);
+    }
 
-                // Wake a writer, if there's any waiting.
-                wake_one(&self.state);
+    pub(crate) fn upgrade(&self) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            // No other readers left.
+            if readers(s) == 1 {
+                match self.state.compare_exchange_weak(
+                    s,
+                    s - READER - UPGRADABLE + WRITE_LOCKED,
+                    Acquire,
+                    Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => s = e,
+                }
+                continue;
+            }
+            // Wait like a writer. This also blocks new readers, if writers are
+            // preferred.
+            if s & WRITERS_WAITING == 0 {
+                if let Err(e) =
+                    self.state
+                        .compare_exchange(s, s | WRITERS_WAITING, Relaxed, Relaxed)
+                {
+                    s = e;
+                    continue;
+                }
             }
-            Err(_) => {
-                // A new reader has been added. Don't unlock.
+            let w = self.writer_wake_counter.load(Acquire);
+            s = self.state.load(Relaxed);
+            if s & WRITERS_WAITING != 0 && readers(s) > 1 {
+                wait(&self.writer_wake_counter, w);
+                s = self.state.load(Relaxed);
+            }
+        }
+    }
+
+    /// Upgrade only if there are no other readers left.
+    pub(crate) fn try_upgrade(&self) -> bool {
+        let mut s = self.state.load(Relaxed);
+        while readers(s) == 1 {
+            match self.state.compare_exchange_weak(
+                s,
+                s - READER - UPGRADABLE + WRITE_LOCKED,
+                Acquire,
+                Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(e) => s = e,
             }
         }
+        false
     }
 
     #[inline]
-    fn write_unlock(&self) {
-        self.state.store(UNLOCKED, Release);
+    pub(crate) fn write_unlock(&self) {
+        if self.state.swap(UNLOCKED, Release) & WRITERS_WAITING != 0 {
+            self.wake_writer();
+        }
+        // There's no way to know if there are readers waiting.
+        wake_all(&self.state);
+    }
+
+    fn wake_writer(&self) {
+        self.writer_wake_counter.fetch_add(1, Release);
+        wake_one(&self.writer_wake_counter);
+    }
+
+    /// Swap the write lock for a read lock in a single step, so that no
+    /// writer can get in between.
+    pub(crate) fn downgrade(&self) {
+        self.state.fetch_add(READER - WRITE_LOCKED, Release);
+        // Let the waiting readers in.
         wake_all(&self.state);
     }
 }
 
-pub struct RLockGuard<'a, T> {
-    lock: &'a RwLock<T>,
+unsafe impl lock::RawRwLock for RawRwLock {
+    const INIT: Self = Self::new(false);
+
+    fn read(&self) {
+        RawRwLock::read(self);
+    }
+
+    fn try_read(&self) -> bool {
+        RawRwLock::try_read(self)
+    }
+
+    unsafe fn read_unlock(&self) {
+        RawRwLock::read_unlock(self);
+    }
+
+    fn write(&self) {
+        RawRwLock::write(self);
+    }
+
+    fn try_write(&self) -> bool {
+        RawRwLock::try_write(self)
+    }
+
+    unsafe fn write_unlock(&self) {
+        RawRwLock::write_unlock(self);
+    }
 }
 
-impl<'a, T> Deref for RLockGuard<'a, T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.lock.data.get() }
+unsafe impl lock::RawTimedRwLock for RawRwLock {
+    fn try_read_until(&self, deadline: Instant) -> bool {
+        RawRwLock::try_read_until(self, deadline)
+    }
+
+    fn try_write_until(&self, deadline: Instant) -> bool {
+        RawRwLock::try_write_until(self, deadline)
     }
 }
 
-impl<'a, T> Drop for RLockGuard<'a, T> {
-    fn drop(&mut self) {
-        self.lock.read_unlock();
+unsafe impl lock::RawUpgradableRwLock for RawRwLock {
+    fn upgradable_read(&self) {
+        RawRwLock::upgradable_read(self);
+    }
+
+    fn try_upgradable_read(&self) -> bool {
+        RawRwLock::try_upgradable_read(self)
+    }
+
+    unsafe fn upgradable_unlock(&self) {
+        RawRwLock::upgradable_unlock(self);
+    }
+
+    unsafe fn upgrade(&self) {
+        RawRwLock::upgrade(self);
+    }
+
+    unsafe fn try_upgrade(&self) -> bool {
+        RawRwLock::try_upgrade(self)
     }
 }
 
-pub struct WLockGuard<'a, T> {
-    lock: &'a RwLock<T>,
+unsafe impl lock::RawDowngradableRwLock for RawRwLock {
+    unsafe fn downgrade(&self) {
+        RawRwLock::downgrade(self);
+    }
+}
+
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawRwLock for RawRwLock {
+    const INIT: Self = Self::new(false);
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        self.read();
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.try_read()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.read_unlock();
+    }
+
+    fn lock_exclusive(&self) {
+        self.write();
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.try_write()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.write_unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) & !WRITERS_WAITING != 0
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Relaxed) & WRITE_LOCKED != 0
+    }
+}
+
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawRwLockDowngrade for RawRwLock {
+    unsafe fn downgrade(&self) {
+        RawRwLock::downgrade(self);
+    }
 }
 
-impl<'a, T> Deref for WLockGuard<'a, T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.lock.data.get() }
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
+    fn lock_upgradable(&self) {
+        self.upgradable_read();
+    }
+
+    fn try_lock_upgradable(&self) -> bool {
+        self.try_upgradable_read()
+    }
+
+    unsafe fn unlock_upgradable(&self) {
+        self.upgradable_unlock();
+    }
+
+    unsafe fn upgrade(&self) {
+        RawRwLock::upgrade(self);
+    }
+
+    unsafe fn try_upgrade(&self) -> bool {
+        RawRwLock::try_upgrade(self)
     }
 }
 
-impl<'a, T> DerefMut for WLockGuard<'a, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.lock.data.get() }
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawRwLockTimed for RawRwLock {
+    type Duration = std::time::Duration;
+    type Instant = Instant;
+
+    fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+        lock::RawTimedRwLock::try_read_for(self, timeout)
+    }
+
+    fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+        self.try_read_until(deadline)
+    }
+
+    fn try_lock_exclusive_for(&self, timeout: Self::Duration) -> bool {
+        lock::RawTimedRwLock::try_write_for(self, timeout)
+    }
+
+    fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+        self.try_write_until(deadline)
     }
 }
 
-impl<'a, T> Drop for WLockGuard<'a, T> {
-    fn drop(&mut self) {
-        self.lock.write_unlock();
+/// Like `Mutex`, a lock created with `RwLock::new_poisoning` is poisoned by a
+/// thread that panics while holding a write guard. Only `read_checked` and
+/// `write_checked` report it.
+///
+/// By default, readers can keep the lock as long as there's at least one of
+/// them around, starving any writer. A lock created with
+/// `new_writer_preferring` instead makes new readers wait while a writer is
+/// waiting.
+pub type RwLock<T> = lock::RwLock<RawRwLock, T>;
+
+pub type RLockGuard<'a, T> = lock::ReadGuard<'a, RawRwLock, T>;
+pub type UpgradableGuard<'a, T> = lock::UpgradableGuard<'a, RawRwLock, T>;
+pub type WLockGuard<'a, T> = lock::WriteGuard<'a, RawRwLock, T>;
+
+/// An owned `RLockGuard`, created by `RwLock::read_arc`.
+pub type ArcRLockGuard<T> = lock::ArcReadGuard<RawRwLock, T>;
+/// An owned `WLockGuard`, created by `RwLock::write_arc`.
+pub type ArcWLockGuard<T> = lock::ArcWriteGuard<RawRwLock, T>;
+
+/// An `RLockGuard` for a part of the data, created by `RLockGuard::map`.
+pub type MappedRLockGuard<'a, T> = lock::MappedReadGuard<'a, RawRwLock, T>;
+/// A `WLockGuard` for a part of the data, created by `WLockGuard::map`.
+pub type MappedWLockGuard<'a, T> = lock::MappedWriteGuard<'a, RawRwLock, T>;
+
+impl<T> RwLock<T> {
+    /// A lock where waiting writers block new readers, so that a steady
+    /// stream of readers can't starve the writers.
+    ///
+    /// Note that this means a thread that already holds a read guard can
+    /// deadlock by calling `read` again, if a writer started waiting in the
+    /// meantime.
+    pub const fn new_writer_preferring(data: T) -> Self {
+        Self::from_raw(RawRwLock::new(true), data)
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::{
+        sync::atomic::{AtomicBool, Ordering::*},
         thread,
         time::{Duration, Instant},
     };
 
-    use super::RwLock;
+    use super::{RLockGuard, RwLock, UpgradableGuard, WLockGuard};
+    use crate::arc::Arc;
 
     #[test]
     fn multiple_readers() {
@@ -251,4 +640,305 @@ mod test {
             assert!(writer_2 > Duration::from_millis(500));
         });
     }
+
+    #[test]
+    fn writer_wakes_writers() {
+        let lock = RwLock::new(0);
+        thread::scope(|s| {
+            let guard = lock.write();
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        *lock.write() += 1;
+                    }
+                });
+            }
+            thread::sleep(Duration::from_millis(10));
+            drop(guard);
+        });
+        assert_eq!(*lock.read(), 400);
+    }
+
+    #[test]
+    fn upgradable_read() {
+        let lock = RwLock::new(0);
+        thread::scope(|s| {
+            let upgradable = lock.upgradable_read();
+            // Plain readers can still get in.
+            s.spawn(|| assert_eq!(*lock.read(), 0)).join().unwrap();
+
+            let upgradable_2 = s.spawn(|| {
+                let start = Instant::now();
+                let guard = lock.upgradable_read();
+                (start.elapsed(), *guard)
+            });
+            thread::sleep(Duration::from_millis(100));
+            *UpgradableGuard::upgrade(upgradable) = 1;
+
+            let (elapsed, value) = upgradable_2.join().unwrap();
+            assert!(elapsed >= Duration::from_millis(100));
+            assert_eq!(value, 1);
+        });
+    }
+
+    #[test]
+    fn upgrade_waits_for_readers() {
+        let lock = RwLock::new_writer_preferring(0);
+        thread::scope(|s| {
+            let reader = lock.read();
+            let upgrader = s.spawn(|| {
+                let guard = lock.upgradable_read();
+                let start = Instant::now();
+                let mut guard = UpgradableGuard::upgrade(guard);
+                *guard = 1;
+                start.elapsed()
+            });
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(*reader, 0);
+            drop(reader);
+            assert!(upgrader.join().unwrap() >= Duration::from_millis(100));
+        });
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn try_upgrade() {
+        let lock = RwLock::new(0);
+        let reader = lock.read();
+        let upgradable = lock.try_upgradable_read().unwrap();
+        assert!(lock.try_upgradable_read().is_none());
+        let upgradable = UpgradableGuard::try_upgrade(upgradable).err().unwrap();
+        drop(reader);
+        *UpgradableGuard::try_upgrade(upgradable).ok().unwrap() = 1;
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn downgrade() {
+        let lock = RwLock::new(0);
+        thread::scope(|s| {
+            let mut writer = lock.write();
+            let writer_2 = s.spawn(|| {
+                let mut guard = lock.write();
+                *guard = 2;
+            });
+            thread::sleep(Duration::from_millis(10));
+            *writer = 1;
+
+            let reader = WLockGuard::downgrade(writer);
+            // Other readers can join, but the waiting writer can't.
+            s.spawn(|| assert_eq!(*lock.read(), 1)).join().unwrap();
+            assert_eq!(*reader, 1);
+            drop(reader);
+            writer_2.join().unwrap();
+        });
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn try_read_write() {
+        let lock = RwLock::new(0);
+        let reader = lock.try_read().unwrap();
+        assert!(lock.try_read().is_some());
+        assert!(lock.try_write().is_none());
+        drop(reader);
+
+        let writer = lock.try_write().unwrap();
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(writer);
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn timed_read_write() {
+        let lock = RwLock::new(0);
+        let reader = lock.try_read_for(Duration::from_millis(10)).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let reader_2 = lock.try_read_until(deadline).unwrap();
+        // Both readers are holding the lock.
+        assert!(lock.try_write().is_none());
+        drop(reader);
+        assert!(lock.try_write().is_none());
+        drop(reader_2);
+
+        let writer = lock.try_write_for(Duration::from_millis(10)).unwrap();
+        assert!(lock.try_read().is_none());
+        drop(writer);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        drop(lock.try_write_until(deadline).unwrap());
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn timed_out_reader() {
+        let lock = RwLock::new(0);
+        let writer = lock.write();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let start = Instant::now();
+                assert!(lock.try_read_for(Duration::from_millis(50)).is_none());
+                assert!(start.elapsed() >= Duration::from_millis(50));
+            });
+        });
+        drop(writer);
+        // No reader count left behind.
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn timed_out_writer() {
+        let lock = RwLock::new_writer_preferring(0);
+        let reader = lock.read();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let start = Instant::now();
+                assert!(lock.try_write_for(Duration::from_millis(50)).is_none());
+                assert!(start.elapsed() >= Duration::from_millis(50));
+            });
+        });
+        // The writer gave up, so it shouldn't block readers anymore.
+        assert!(lock.try_read().is_some());
+        drop(reader);
+        assert_eq!(*lock.try_write_for(Duration::from_secs(1)).unwrap(), 0);
+    }
+
+    #[test]
+    fn map() {
+        let lock = RwLock::new((1, String::from("abc")));
+        let mut first = WLockGuard::map(lock.write(), |data| &mut data.0);
+        *first = 2;
+        assert!(lock.try_read().is_none());
+        drop(first);
+
+        let name = RLockGuard::map(lock.read(), |data| &data.1[..2]);
+        assert_eq!(&*name, "ab");
+        assert!(lock.try_read().is_some());
+        assert!(lock.try_write().is_none());
+        drop(name);
+
+        assert_eq!(lock.try_write().unwrap().0, 2);
+    }
+
+    #[test]
+    fn get_mut_into_inner() {
+        let mut lock = RwLock::new(vec![1]);
+        lock.get_mut().push(2);
+        lock.write().push(3);
+        assert_eq!(lock.into_inner(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn arc_guards() {
+        let lock = Arc::new(RwLock::new(0));
+        let reader = RwLock::read_arc(&lock);
+        let reader_2 = thread::spawn({
+            let lock = Arc::clone(&lock);
+            move || RwLock::read_arc(&lock)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(*reader + *reader_2, 0);
+        assert!(lock.try_write().is_none());
+        drop((reader, reader_2));
+
+        let mut writer = RwLock::write_arc(&lock);
+        thread::spawn(move || *writer = 1).join().unwrap();
+        assert_eq!(Arc::strong_count(&lock), 1);
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn poison() {
+        let lock = RwLock::new_poisoning(0);
+        thread::scope(|s| {
+            // Panicking with a read guard doesn't poison the lock.
+            let t = s.spawn(|| {
+                let _guard = lock.read();
+                panic!("oops");
+            });
+            assert!(t.join().is_err());
+            assert!(!lock.is_poisoned());
+
+            // A write guard does, even one from plain `write`.
+            let t = s.spawn(|| {
+                let mut guard = lock.write();
+                *guard = 1;
+                panic!("oops");
+            });
+            assert!(t.join().is_err());
+        });
+        assert!(lock.is_poisoned());
+        assert_eq!(*lock.read(), 1);
+        assert!(lock.read_checked().is_err());
+
+        let mut guard = lock.write_checked().err().unwrap().into_inner();
+        *guard = 2;
+        drop(guard);
+
+        lock.clear_poison();
+        assert_eq!(*lock.read_checked().unwrap(), 2);
+
+        // A lock from `new` never gets poisoned.
+        let lock = RwLock::new(0);
+        thread::scope(|s| {
+            let t = s.spawn(|| {
+                let _guard = lock.write_checked().unwrap();
+                panic!("oops");
+            });
+            assert!(t.join().is_err());
+        });
+        assert!(!lock.is_poisoned());
+    }
+
+    #[test]
+    fn writer_preferring() {
+        let lock = RwLock::new_writer_preferring(0);
+        let done = AtomicBool::new(false);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        thread::scope(|s| {
+            // Overlapping readers, so that the lock is never free if the
+            // readers get their way.
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Relaxed) && Instant::now() < deadline {
+                        let _guard = lock.read();
+                        thread::sleep(Duration::from_millis(2));
+                    }
+                });
+            }
+            thread::sleep(Duration::from_millis(50));
+
+            for _ in 0..10 {
+                *lock.write() += 1;
+                thread::sleep(Duration::from_millis(5));
+            }
+            done.store(true, Relaxed);
+            assert!(Instant::now() < deadline, "writer starved");
+        });
+        assert_eq!(*lock.read(), 10);
+    }
+
+    #[test]
+    #[cfg(feature = "lock_api")]
+    fn lock_api() {
+        use super::RawRwLock;
+        use lock_api::{RwLockUpgradableReadGuard, RwLockWriteGuard};
+
+        let lock = lock_api::RwLock::<RawRwLock, _>::new(0);
+        let upgradable = lock.upgradable_read();
+        let reader = lock.read();
+        assert!(lock.try_upgradable_read().is_none());
+        let upgradable = RwLockUpgradableReadGuard::try_upgrade(upgradable).unwrap_err();
+        drop(reader);
+        let mut writer = RwLockUpgradableReadGuard::try_upgrade(upgradable).unwrap();
+        *writer += 1;
+        assert!(lock.is_locked_exclusive());
+        assert!(lock.try_read_for(Duration::from_millis(10)).is_none());
+        let reader = RwLockWriteGuard::downgrade(writer);
+        assert_eq!(*reader, 1);
+        assert_eq!(*lock.try_read().unwrap(), 1);
+        drop(reader);
+        assert!(!lock.is_locked());
+    }
 }
@@ -1,64 +1,189 @@
 use std::{
-    cell::UnsafeCell,
-    ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
 };
 
-pub struct SpinLock<T> {
-    locked: AtomicBool,
-    data: UnsafeCell<T>,
+use atomic_wait::{wait, wake_one};
+
+use crate::lock::{self, Lock, LockGuard, MappedLockGuard};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and there may be threads parked on the lock.
+const PARKED: u32 = 2;
+
+/// What a thread waiting for a `SpinLock` does next.
+pub enum Action {
+    /// Spin this many times, before trying again.
+    Spin(u32),
+    /// Let the OS run another thread, before trying again.
+    Yield,
+    /// Stop spinning, and sleep until the lock is released.
+    Park,
+}
+
+/// How a thread waits for a `SpinLock` to be released.
+pub trait Backoff {
+    /// The lock has been found locked `spins` times in a row.
+    fn backoff(spins: u32) -> Action;
+}
+
+/// Spin forever. The default.
+pub struct Spin;
+
+impl Backoff for Spin {
+    fn backoff(_spins: u32) -> Action {
+        Action::Spin(1)
+    }
+}
+
+/// Spin twice as long after every failed attempt, up to `2^10` times.
+pub struct Exponential;
+
+impl Backoff for Exponential {
+    fn backoff(spins: u32) -> Action {
+        Action::Spin(1 << spins.min(10))
+    }
+}
+
+/// Spin `N` times, and then yield to other threads.
+pub struct YieldAfter<const N: u32 = 100>;
+
+impl<const N: u32> Backoff for YieldAfter<N> {
+    fn backoff(spins: u32) -> Action {
+        if spins < N {
+            Action::Spin(1)
+        } else {
+            Action::Yield
+        }
+    }
 }
 
-unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+/// Spin `N` times, and then park until the lock is released.
+pub struct ParkAfter<const N: u32 = 100>;
 
-impl<T> SpinLock<T> {
-    pub fn new(data: T) -> Self {
+impl<const N: u32> Backoff for ParkAfter<N> {
+    fn backoff(spins: u32) -> Action {
+        if spins < N {
+            Action::Spin(1)
+        } else {
+            Action::Park
+        }
+    }
+}
+
+/// The lock behind `SpinLock`, without the data.
+pub struct RawSpinLock<B = Spin> {
+    /// 0: unlocked
+    /// 1: locked
+    /// 2: locked, with threads possibly parked
+    state: AtomicU32,
+    _backoff: PhantomData<fn() -> B>,
+}
+
+impl<B: Backoff> RawSpinLock<B> {
+    pub const fn new() -> Self {
         Self {
-            locked: AtomicBool::new(false),
-            data: UnsafeCell::new(data),
+            state: AtomicU32::new(UNLOCKED),
+            _backoff: PhantomData,
         }
     }
 
-    pub fn lock(&self) -> SpinLockGuard<'_, T> {
-        while self.locked.swap(true, Ordering::Acquire) {
-            std::hint::spin_loop();
+    pub fn lock(&self) {
+        let mut spins = 0;
+        loop {
+            // Test-and-test-and-set: only try to take the lock when it looks
+            // free. Loading keeps the cache line shared between the waiting
+            // threads, while every swap would take it over exclusively.
+            if self.state.load(Ordering::Relaxed) == UNLOCKED
+                && self
+                    .state
+                    .compare_exchange_weak(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            match B::backoff(spins) {
+                Action::Spin(n) => {
+                    for _ in 0..n {
+                        std::hint::spin_loop();
+                    }
+                }
+                Action::Yield => thread::yield_now(),
+                Action::Park => return self.lock_parked(),
+            }
+            spins = spins.saturating_add(1);
         }
-        SpinLockGuard { lock: &self }
     }
+}
 
-    fn unlock(&self) {
-        self.locked.store(false, Ordering::Release);
+impl<B> RawSpinLock<B> {
+    pub fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+    #[cold]
+    fn lock_parked(&self) {
+        // Like a contended `Mutex`: a thread that parks can't tell whether
+        // it's the last one to, so it always leaves the lock marked `PARKED`.
+        while self.state.swap(PARKED, Ordering::Acquire) != UNLOCKED {
+            wait(&self.state, PARKED);
+        }
+    }
+
+    pub(crate) fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == PARKED {
+            wake_one(&self.state);
+        }
     }
 }
 
-pub struct SpinLockGuard<'a, T> {
-    lock: &'a SpinLock<T>,
+impl<B: Backoff> Default for RawSpinLock<B> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<'a, T> Deref for SpinLockGuard<'a, T> {
-    type Target = T;
+unsafe impl<B: Backoff> lock::RawLock for RawSpinLock<B> {
+    const INIT: Self = Self::new();
 
-    fn deref(&self) -> &Self::Target {
-        // Safety: The existence of this Guard instance guarantees this is the
-        // exclusive reference to the data.
-        unsafe { &*self.lock.data.get() }
+    fn lock(&self) {
+        RawSpinLock::lock(self);
     }
-}
 
-impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        // Safety: The existence of this Guard instance guarantees this is the
-        // exclusive reference to the data.
-        unsafe { &mut *self.lock.data.get() }
+    unsafe fn unlock(&self) {
+        RawSpinLock::unlock(self);
+    }
+
+    fn is_locked(&self) -> bool {
+        RawSpinLock::is_locked(self)
     }
 }
 
-impl<'a, T> Drop for SpinLockGuard<'a, T> {
-    fn drop(&mut self) {
-        self.lock.unlock();
+unsafe impl<B: Backoff> lock::RawTryLock for RawSpinLock<B> {
+    fn try_lock(&self) -> bool {
+        RawSpinLock::try_lock(self)
     }
 }
 
+/// A spin lock, with the default `Spin` backoff.
+///
+/// For another `Backoff` strategy, use `Lock<RawSpinLock<B>, T>` directly:
+/// a default `B` on this alias wouldn't apply to `SpinLock::new(data)`.
+pub type SpinLock<T> = Lock<RawSpinLock, T>;
+
+pub type SpinLockGuard<'a, T> = LockGuard<'a, RawSpinLock, T>;
+
+/// A `SpinLockGuard` for a part of the data, created by `SpinLockGuard::map`.
+pub type MappedSpinLockGuard<'a, T> = MappedLockGuard<'a, RawSpinLock, T>;
+
 pub mod check {
     use super::SpinLock;
     use std::{thread, time::Duration};
@@ -87,13 +212,18 @@ pub mod check {
 
 #[cfg(test)]
 mod test {
-    use super::SpinLock;
+    use super::{
+        Backoff, Exponential, ParkAfter, RawSpinLock, Spin, SpinLock, SpinLockGuard, YieldAfter,
+    };
+    use crate::lock::Lock;
     use std::{thread, time::Duration};
 
+    static COUNTER: SpinLock<usize> = SpinLock::new(0);
+
     /// This is probably not going to be conclusive!
     #[test]
     fn add() {
-        let lock = SpinLock::new(0 as usize);
+        let lock = SpinLock::new(0_usize);
         const THREADS: usize = 100;
         thread::scope(|s| {
             for _ in 0..THREADS {
@@ -106,4 +236,66 @@ mod test {
         });
         assert_eq!(*lock.lock(), THREADS);
     }
+
+    #[test]
+    fn map() {
+        let lock = SpinLock::new((1, 2));
+        let mut second = SpinLockGuard::map(lock.lock(), |data| &mut data.1);
+        *second = 3;
+        drop(second);
+        assert_eq!(*lock.lock(), (1, 3));
+    }
+
+    fn count<B: Backoff>() {
+        let lock = Lock::<RawSpinLock<B>, _>::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 8000);
+    }
+
+    #[test]
+    fn backoff() {
+        count::<Spin>();
+        count::<Exponential>();
+        count::<YieldAfter>();
+        count::<YieldAfter<0>>();
+        count::<ParkAfter>();
+        count::<ParkAfter<0>>();
+    }
+
+    #[test]
+    fn try_lock() {
+        let lock = SpinLock::new(0);
+        assert!(!lock.is_locked());
+        let guard = lock.try_lock().unwrap();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(!lock.is_locked());
+        assert!(lock.try_lock().is_some());
+    }
+
+    #[test]
+    fn get_mut_into_inner() {
+        let mut lock = SpinLock::new(vec![1]);
+        lock.get_mut().push(2);
+        assert_eq!(lock.into_inner(), vec![1, 2]);
+    }
+
+    #[test]
+    fn in_static() {
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| *COUNTER.lock() += 1);
+            }
+        });
+        assert_eq!(*COUNTER.lock(), 4);
+    }
 }
@@ -0,0 +1,153 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::lock::{self, Lock, LockGuard, MappedLockGuard};
+
+/// A counter on a cache line of its own, so that taking a ticket doesn't
+/// disturb the threads spinning on the other counter.
+#[repr(align(64))]
+struct Counter(AtomicU32);
+
+/// The lock behind `TicketLock`, without the data.
+pub struct RawTicketLock {
+    /// The ticket handed out to the next thread that tries to lock.
+    next_ticket: Counter,
+    /// The ticket of the thread holding the lock (or allowed to take it).
+    now_serving: Counter,
+}
+
+impl RawTicketLock {
+    pub const fn new() -> Self {
+        Self {
+            next_ticket: Counter(AtomicU32::new(0)),
+            now_serving: Counter(AtomicU32::new(0)),
+        }
+    }
+
+    pub fn lock(&self) {
+        // Tickets wrap around, which is fine as long as fewer than 2^32
+        // threads are waiting.
+        let ticket = self.next_ticket.0.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.0.load(Ordering::Acquire) != ticket {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Take the lock only if nobody is holding it or waiting for it.
+    pub fn try_lock(&self) -> bool {
+        // Acquire: if the ticket being served is the next one, the thread
+        // that held the lock released it by storing to `now_serving`.
+        let serving = self.now_serving.0.load(Ordering::Acquire);
+        self.next_ticket
+            .0
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    /// Whether any thread holds the lock. It may have changed by the time
+    /// this returns.
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.0.load(Ordering::Relaxed) != self.now_serving.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn unlock(&self) {
+        // Only the thread holding the lock changes `now_serving`.
+        let serving = self.now_serving.0.load(Ordering::Relaxed);
+        self.now_serving
+            .0
+            .store(serving.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl Default for RawTicketLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl lock::RawLock for RawTicketLock {
+    const INIT: Self = Self::new();
+
+    fn lock(&self) {
+        RawTicketLock::lock(self);
+    }
+
+    unsafe fn unlock(&self) {
+        RawTicketLock::unlock(self);
+    }
+
+    fn is_locked(&self) -> bool {
+        RawTicketLock::is_locked(self)
+    }
+}
+
+unsafe impl lock::RawTryLock for RawTicketLock {
+    fn try_lock(&self) -> bool {
+        RawTicketLock::try_lock(self)
+    }
+}
+
+/// A spin lock that hands the lock out in the order it was asked for.
+///
+/// A `SpinLock` goes to whichever thread happens to swap first, which can be
+/// the one that just unlocked it, over and over again.
+pub type TicketLock<T> = Lock<RawTicketLock, T>;
+
+pub type TicketLockGuard<'a, T> = LockGuard<'a, RawTicketLock, T>;
+
+/// A `TicketLockGuard` for a part of the data, created by
+/// `TicketLockGuard::map`.
+pub type MappedTicketLockGuard<'a, T> = MappedLockGuard<'a, RawTicketLock, T>;
+
+#[cfg(test)]
+mod test {
+    use super::{TicketLock, TicketLockGuard};
+    use std::{sync::atomic::Ordering::Relaxed, thread};
+
+    #[test]
+    fn add() {
+        let lock = TicketLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 8000);
+    }
+
+    #[test]
+    fn fifo() {
+        const THREADS: u32 = 10;
+        let lock = TicketLock::new(Vec::new());
+        let guard = lock.lock();
+        thread::scope(|s| {
+            for i in 0..THREADS {
+                s.spawn({
+                    let lock = &lock;
+                    move || lock.lock().push(i)
+                });
+                // Let thread `i` take its ticket, before starting the next.
+                while lock.raw().next_ticket.0.load(Relaxed) != i + 2 {
+                    thread::yield_now();
+                }
+            }
+            drop(guard);
+        });
+        assert_eq!(*lock.lock(), (0..THREADS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn map() {
+        let lock = TicketLock::new((1, 2));
+        *TicketLockGuard::map(lock.lock(), |data| &mut data.1) = 3;
+        assert_eq!(*lock.lock(), (1, 3));
+    }
+}